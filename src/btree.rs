@@ -1,36 +1,95 @@
+use bincode::{self, deserialize, serialize, serialized_size, Infinite};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
 use serde_ext::BigArray;
+use super::ParseError;
 
 use std::{cmp, fmt, mem, str};
 
 const MAX_UNAME_LENGTH: usize = 32;
 const MAX_EMAIL_LENGTH: usize = 255;
 pub const PAGE_SIZE: usize = 4096;
+/// Bytes at the end of every page holding a checksum of the rest of it.
+pub const PAGE_CHECKSUM_SIZE: usize = 4;
+/// Most bytes a node may take on disk: a page, less its checksum.
+pub const NODE_SPACE: usize = PAGE_SIZE - PAGE_CHECKSUM_SIZE;
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+/// The type of the id column, which is also the tree's key. Building with
+/// the `wide-keys` feature widens it to 64 bits; files written by one build
+/// can't be read by the other.
+#[cfg(not(feature = "wide-keys"))]
+pub type Key = u32;
+#[cfg(feature = "wide-keys")]
+pub type Key = u64;
+
+/// In memory both strings take their maximum length, so a row can be
+/// overwritten in place. On disk they take only the bytes in use; see the
+/// `Serialize` impl.
+#[derive(Copy, Clone)]
 pub struct Row {
-    pub id: u32,
+    pub id: Key,
     username_len: u8,
     username: [u8; MAX_UNAME_LENGTH as usize],
     email_len: u8,
-    #[serde(with = "BigArray")] email: [u8; MAX_EMAIL_LENGTH as usize],
+    email: [u8; MAX_EMAIL_LENGTH as usize],
     // _pad: [u8; 3],
 }
 
 impl Row {
-    pub fn new(id: u32, username: &[u8], email: &[u8]) -> Self {
+    pub fn new(id: Key, username: &[u8], email: &[u8]) -> Self {
+        let mut row = Row::default();
+        row.fill(id, username, email);
+        row
+    }
+
+    /// Overwrites this row in place, truncating over-long fields.
+    pub fn fill(&mut self, id: Key, username: &[u8], email: &[u8]) {
         let username_len = cmp::min(MAX_UNAME_LENGTH, username.len());
         let email_len = cmp::min(MAX_EMAIL_LENGTH, email.len());
-        let mut row = Row {
-            id: id,
-            username_len: username_len as u8,
-            username: [0; MAX_UNAME_LENGTH as usize],
-            email_len: email_len as u8,
-            email: [0; MAX_EMAIL_LENGTH as usize],
-            // _pad: Default::default(),
-        };
-        row.username[..username_len].copy_from_slice(&username[..username_len]);
-        row.email[..email_len].copy_from_slice(&email[..email_len]);
-        row
+        self.id = id;
+        self.username_len = username_len as u8;
+        self.username = [0; MAX_UNAME_LENGTH];
+        self.username[..username_len].copy_from_slice(&username[..username_len]);
+        self.email_len = email_len as u8;
+        self.email = [0; MAX_EMAIL_LENGTH];
+        self.email[..email_len].copy_from_slice(&email[..email_len]);
+    }
+
+    /// Checks the invariants `fill` maintains, for rows that arrived some
+    /// other way, e.g. decoded from bytes.
+    pub fn validate(&self) -> Result<(), ParseError> {
+        if self.username_len as usize > MAX_UNAME_LENGTH
+            || self.email_len as usize > MAX_EMAIL_LENGTH
+        {
+            return Err(ParseError::StringTooLong);
+        }
+        if str::from_utf8(self.username()).is_err() || str::from_utf8(self.email()).is_err() {
+            return Err(ParseError::InvalidUtf8);
+        }
+        Ok(())
+    }
+
+    pub fn username(&self) -> &[u8] {
+        &self.username[..self.username_len as usize]
+    }
+
+    pub fn email(&self) -> &[u8] {
+        &self.email[..self.email_len as usize]
+    }
+
+    /// How many bytes the row takes on disk.
+    pub fn encoded_len(&self) -> usize {
+        mem::size_of::<Key>() + 1 + self.username_len as usize + 1 + self.email_len as usize
+    }
+
+    /// Encodes the row exactly as it is laid out inside a page on disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(self, Infinite).expect("serializing a Row into memory cannot fail")
+    }
+
+    /// Decodes a row from its on-disk encoding, e.g. one cut out of a page dump.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Row, bincode::Error> {
+        deserialize(bytes)
     }
 }
 
@@ -47,6 +106,79 @@ impl Default for Row {
     }
 }
 
+/// The id, then each string as its length byte followed by just that many
+/// bytes.
+impl Serialize for Row {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let len = 2 + self.username_len as usize + 1 + self.email_len as usize;
+        let mut tuple = serializer.serialize_tuple(len)?;
+        tuple.serialize_element(&self.id)?;
+        tuple.serialize_element(&self.username_len)?;
+        for byte in self.username() {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.serialize_element(&self.email_len)?;
+        for byte in self.email() {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Row {
+    fn deserialize<D>(deserializer: D) -> Result<Row, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RowVisitor;
+
+        impl<'de> Visitor<'de> for RowVisitor {
+            type Value = Row;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a row")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Row, A::Error>
+            where
+                A: SeqAccess<'de>,
+            {
+                let mut read = 0;
+                let mut next = |seq: &mut A| -> Result<u8, A::Error> {
+                    read += 1;
+                    seq.next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(read, &RowVisitor))
+                };
+                let id = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &RowVisitor))?;
+                let mut row = Row {
+                    id,
+                    ..Row::default()
+                };
+                row.username_len = next(&mut seq)?;
+                if row.username_len as usize > MAX_UNAME_LENGTH {
+                    return Err(de::Error::custom("username too long"));
+                }
+                for i in 0..row.username_len as usize {
+                    row.username[i] = next(&mut seq)?;
+                }
+                row.email_len = next(&mut seq)?;
+                for i in 0..row.email_len as usize {
+                    row.email[i] = next(&mut seq)?;
+                }
+                Ok(row)
+            }
+        }
+
+        // The most elements a row can have; shorter ones stop early.
+        let max_len = 2 + MAX_UNAME_LENGTH + 1 + MAX_EMAIL_LENGTH;
+        deserializer.deserialize_tuple(max_len, RowVisitor)
+    }
+}
+
 impl fmt::Display for Row {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match (
@@ -59,48 +191,219 @@ impl fmt::Display for Row {
     }
 }
 
+/// An owned, trimmed copy of a `Row` for programmatic consumers, without the
+/// fixed-size storage arrays.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PublicRow {
+    pub id: Key,
+    pub username: String,
+    pub email: String,
+}
+
+impl From<Row> for PublicRow {
+    fn from(row: Row) -> Self {
+        PublicRow {
+            id: row.id,
+            username: String::from_utf8_lossy(row.username()).into_owned(),
+            email: String::from_utf8_lossy(row.email()).into_owned(),
+        }
+    }
+}
+
+/// A leaf cell. The row's own id doubles as the key, so it isn't stored twice.
 #[derive(Default, Serialize, Deserialize)]
-pub struct Cell(pub u32, pub Row);
+pub struct Cell(pub Row);
 
 impl Cell {
-    pub fn set_key(&mut self, key: u32) {
-        self.0 = key;
+    pub fn key(&self) -> Key {
+        self.0.id
     }
     pub fn set_val(&mut self, val: &Row) {
-        self.1 = *val;
+        self.0 = *val;
     }
 }
 
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct NodeHeader {
-    is_root: bool,
-    parent_ptr: u32,
+    pub is_root: bool,
+    /// Page number of the parent. Meaningless for the root.
+    pub parent_ptr: u32,
+}
+
+/// A separator in an internal node: `child` holds the keys no greater than
+/// `key` (and greater than the previous cell's key).
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct InternalCell {
+    pub child: u32,
+    pub key: Key,
 }
 
-pub const LEAF_NODE_MAX_CELLS: usize =
-    (PAGE_SIZE - mem::size_of::<NodeHeader>() - 4) / mem::size_of::<Cell>();
+/// As many internal cells as fit in a page, after the variant tag, the
+/// header, `num_keys` and `right_child`.
+#[cfg(not(test))]
+pub const INTERNAL_NODE_MAX_CELLS: usize =
+    (NODE_SPACE - 4 - mem::size_of::<bool>() - 4 - 4 - 4) / (4 + mem::size_of::<Key>());
+/// Few enough that even small test tables grow several levels deep, which
+/// keeps the multi-level code paths exercised.
+#[cfg(test)]
+pub const INTERNAL_NODE_MAX_CELLS: usize = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum NodeError {
+    CellOutOfBounds { cell_num: u32, num_cells: u32 },
+    /// The node has no room left; split it instead.
+    Full,
+    /// An internal node was asked about a child it doesn't have.
+    NoSuchChild(u32),
+    /// A row operation was given an internal node, which holds no rows.
+    NotALeaf,
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum Node {
     Leaf {
         header: NodeHeader,
         num_cells: u32,
-        cells: [Cell; LEAF_NODE_MAX_CELLS],
+        /// Page number of the next leaf in key order, or 0 (never a leaf) for
+        /// the last one.
+        next_leaf: u32,
+        /// Exactly `num_cells` cells, as many as fit in a page.
+        cells: Vec<Cell>,
+    },
+    /// `num_keys` separators, plus `right_child` for the keys above all of them.
+    Internal {
+        header: NodeHeader,
+        num_keys: u32,
+        right_child: u32,
+        /// Boxed, so that a leaf doesn't take a whole page of memory too.
+        #[serde(with = "BigArray")] cells: Box<[InternalCell; INTERNAL_NODE_MAX_CELLS]>,
     },
-    Internal
 }
 
 impl Node {
     pub fn create_leaf() -> Self {
         Node::Leaf {
             header: Default::default(),
-            cells: Default::default(),
+            cells: Vec::new(),
             num_cells: 0,
+            next_leaf: 0,
         }
     }
 
-    pub fn insert(&mut self, cell_num: u32, key: u32, val: &Row) {
+    /// An internal node with two children, separated by `key`.
+    pub fn create_internal(left_child: u32, key: Key, right_child: u32) -> Self {
+        let mut cells = Box::new([InternalCell::default(); INTERNAL_NODE_MAX_CELLS]);
+        cells[0] = InternalCell {
+            child: left_child,
+            key,
+        };
+        Node::Internal {
+            header: Default::default(),
+            num_keys: 1,
+            right_child,
+            cells,
+        }
+    }
+
+    /// How many bytes the node takes on disk.
+    pub fn encoded_len(&self) -> usize {
+        serialized_size(self) as usize
+    }
+
+    /// Whether `row` can be added to this leaf without overflowing its page.
+    pub fn has_room_for(&self, row: &Row) -> bool {
+        self.encoded_len() + row.encoded_len() <= NODE_SPACE
+    }
+
+    pub fn header(&self) -> &NodeHeader {
+        match self {
+            &Node::Leaf { ref header, .. } | &Node::Internal { ref header, .. } => header,
+        }
+    }
+
+    pub fn header_mut(&mut self) -> &mut NodeHeader {
+        match self {
+            &mut Node::Leaf { ref mut header, .. } | &mut Node::Internal { ref mut header, .. } => {
+                header
+            }
+        }
+    }
+
+    /// The keys held in the node: row ids in a leaf, separators in an
+    /// internal node.
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            &Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => cells[..num_cells as usize].iter().map(|c| c.key()).collect(),
+            &Node::Internal {
+                num_keys,
+                ref cells,
+                ..
+            } => cells[..num_keys as usize].iter().map(|c| c.key).collect(),
+        }
+    }
+
+    /// The page numbers of an internal node's children, in key order. A leaf
+    /// has none.
+    pub fn children(&self) -> Vec<u32> {
+        match self {
+            &Node::Leaf { .. } => Vec::new(),
+            &Node::Internal {
+                num_keys,
+                right_child,
+                ref cells,
+                ..
+            } => cells[..num_keys as usize]
+                .iter()
+                .map(|c| c.child)
+                .chain(Some(right_child))
+                .collect(),
+        }
+    }
+
+    /// Index into `children()` of the subtree that would hold `key`.
+    pub fn child_index_for(&self, key: Key) -> usize {
+        match self {
+            &Node::Internal {
+                num_keys,
+                ref cells,
+                ..
+            } => match cells[..num_keys as usize].binary_search_by_key(&key, |cell| cell.key) {
+                Ok(idx) | Err(idx) => idx,
+            },
+            _ => unreachable!("leaves have no children"),
+        }
+    }
+
+    /// The page number of the child whose subtree would hold `key`.
+    pub fn child_for(&self, key: Key) -> u32 {
+        match self {
+            &Node::Internal {
+                num_keys,
+                right_child,
+                ref cells,
+                ..
+            } => {
+                let idx = self.child_index_for(key);
+                if idx < num_keys as usize {
+                    cells[idx].child
+                } else {
+                    right_child
+                }
+            }
+            _ => unreachable!("leaves have no children"),
+        }
+    }
+
+    /// Inserts `val` at `cell_num`, shifting later cells right. Fails if
+    /// `cell_num` is past the last cell, and with `NodeError::Full` if the
+    /// leaf has no room left for `val`.
+    pub fn insert(&mut self, cell_num: u32, val: &Row) -> Result<(), NodeError> {
+        let has_room = self.has_room_for(val);
         match self {
             &mut Node::Leaf {
                 ref mut num_cells,
@@ -109,22 +412,343 @@ impl Node {
             } => {
                 let cell_count = *num_cells as usize;
                 let insert_idx = cell_num as usize;
-                if cell_count >= LEAF_NODE_MAX_CELLS {
-                    unimplemented!("Splitting leaf nodes");
+                if insert_idx > cell_count {
+                    return Err(NodeError::CellOutOfBounds {
+                        cell_num,
+                        num_cells: *num_cells,
+                    });
                 }
-                if insert_idx < cell_count {
-                    let (start, end) = (cell_num as usize, cell_count as usize);
-                    for i in (start + 1..end + 1).rev() {
-                        let (left, right) = cells.split_at_mut(i);
-                        mem::swap(&mut left[i - 1], &mut right[0]);
-                    }
+                if !has_room {
+                    return Err(NodeError::Full);
                 }
-                let c = &mut cells[insert_idx];
-                c.set_val(val);
-                c.set_key(key);
+                cells.insert(insert_idx, Cell(*val));
                 *num_cells += 1;
             },
-            _ => {}
+            _ => return Err(NodeError::NotALeaf),
+        }
+        #[cfg(debug_assertions)]
+        self.assert_sorted();
+        Ok(())
+    }
+
+    /// Overwrites the row at `cell_num` with `val`, which has the same key.
+    /// Fails with `NodeError::Full` if `val` is longer and the leaf has no
+    /// room for the difference.
+    pub fn replace(&mut self, cell_num: u32, val: &Row) -> Result<(), NodeError> {
+        let encoded_len = self.encoded_len();
+        match self {
+            &mut Node::Leaf {
+                num_cells,
+                ref mut cells,
+                ..
+            } => {
+                if cell_num >= num_cells {
+                    return Err(NodeError::CellOutOfBounds {
+                        cell_num,
+                        num_cells,
+                    });
+                }
+                let cell = &mut cells[cell_num as usize];
+                if encoded_len - cell.0.encoded_len() + val.encoded_len() > NODE_SPACE {
+                    return Err(NodeError::Full);
+                }
+                cell.set_val(val);
+                Ok(())
+            }
+            _ => Err(NodeError::NotALeaf),
+        }
+    }
+
+    /// Inserts `val` at `cell_num` into a full leaf by splitting it: the lower
+    /// half of the cells, by encoded size, stays here and the upper half moves
+    /// to the returned node, which the caller must give a page, hang off the
+    /// parent and link in as this leaf's next one. Also returns the split
+    /// key, the largest key left in this node.
+    pub fn split_and_insert(
+        &mut self,
+        cell_num: u32,
+        val: &Row,
+    ) -> Result<(Key, Box<Node>), NodeError> {
+        let mut right = Node::create_leaf();
+        let split_key = match (self, &mut right) {
+            (
+                &mut Node::Leaf {
+                    ref header,
+                    ref mut num_cells,
+                    next_leaf,
+                    ref mut cells,
+                },
+                &mut Node::Leaf {
+                    header: ref mut right_header,
+                    num_cells: ref mut right_num_cells,
+                    next_leaf: ref mut right_next_leaf,
+                    cells: ref mut right_cells,
+                },
+            ) => {
+                if cell_num > *num_cells {
+                    return Err(NodeError::CellOutOfBounds {
+                        cell_num,
+                        num_cells: *num_cells,
+                    });
+                }
+                cells.insert(cell_num as usize, Cell(*val));
+                // Rows vary in length, so split where the bytes are halved
+                // rather than the count, keeping both halves within a page.
+                let total: usize = cells.iter().map(|c| c.0.encoded_len()).sum();
+                let mut left_len = 0;
+                let split_at = cells
+                    .iter()
+                    .position(|c| {
+                        left_len += c.0.encoded_len();
+                        2 * left_len >= total
+                    })
+                    .map_or(cells.len(), |idx| idx + 1);
+                *right_cells = cells.split_off(cmp::min(split_at, cells.len() - 1));
+                *num_cells = cells.len() as u32;
+                *right_num_cells = right_cells.len() as u32;
+                right_header.parent_ptr = header.parent_ptr;
+                *right_next_leaf = next_leaf;
+                cells[cells.len() - 1].key()
+            }
+            _ => return Err(NodeError::NotALeaf),
+        };
+        Ok((split_key, Box::new(right)))
+    }
+
+    /// Adds `right` to an internal node as the child just after `left`, with
+    /// `key` separating them, e.g. after `left` split in two. Fails with
+    /// `NodeError::Full` if there is no room for another separator.
+    pub fn insert_child(&mut self, left: u32, key: Key, right: u32) -> Result<(), NodeError> {
+        let (children, keys) = self.children_with(left, key, right)?;
+        if keys.len() > INTERNAL_NODE_MAX_CELLS {
+            return Err(NodeError::Full);
+        }
+        self.set_children(&children, &keys);
+        Ok(())
+    }
+
+    /// Does what `insert_child` does to a full internal node by splitting it:
+    /// the lower children stay here and the upper ones move to the returned
+    /// node. Also returns the key that separates the two halves, which moves
+    /// up to the parent rather than staying in either.
+    pub fn split_and_insert_child(
+        &mut self,
+        left: u32,
+        key: Key,
+        right: u32,
+    ) -> Result<(Key, Box<Node>), NodeError> {
+        let (children, keys) = self.children_with(left, key, right)?;
+        let mid = keys.len() / 2;
+        let mut new_node = Node::create_internal(0, 0, 0);
+        new_node.header_mut().parent_ptr = self.header().parent_ptr;
+        new_node.set_children(&children[mid + 1..], &keys[mid + 1..]);
+        self.set_children(&children[..mid + 1], &keys[..mid]);
+        Ok((keys[mid], Box::new(new_node)))
+    }
+
+    /// Removes `child` from this internal node along with one of the keys
+    /// around it, so its neighbour takes over its range. Returns how many
+    /// children are left; a node left with none is unchanged, and is the
+    /// caller's to remove from the tree.
+    pub fn remove_child(&mut self, child: u32) -> Result<usize, NodeError> {
+        let (mut children, mut keys) = (self.children(), self.keys());
+        let idx = children
+            .iter()
+            .position(|&c| c == child)
+            .ok_or(NodeError::NoSuchChild(child))?;
+        children.remove(idx);
+        if children.is_empty() {
+            return Ok(0);
+        }
+        // The last child's lower bound goes, making the one before it last.
+        keys.remove(cmp::min(idx, keys.len() - 1));
+        self.set_children(&children, &keys);
+        Ok(children.len())
+    }
+
+    /// This internal node's children and keys, with `right` and `key` added
+    /// after `left`.
+    fn children_with(
+        &self,
+        left: u32,
+        key: Key,
+        right: u32,
+    ) -> Result<(Vec<u32>, Vec<Key>), NodeError> {
+        let (mut children, mut keys) = (self.children(), self.keys());
+        let idx = children
+            .iter()
+            .position(|&child| child == left)
+            .ok_or(NodeError::NoSuchChild(left))?;
+        // `left` now ends at `key`; `right` takes over its old upper bound.
+        keys.insert(idx, key);
+        children.insert(idx + 1, right);
+        Ok((children, keys))
+    }
+
+    /// Replaces an internal node's contents with `children` and the `keys`
+    /// between them.
+    fn set_children(&mut self, children: &[u32], keys: &[Key]) {
+        debug_assert_eq!(children.len(), keys.len() + 1);
+        if let &mut Node::Internal {
+            ref mut num_keys,
+            ref mut right_child,
+            ref mut cells,
+            ..
+        } = self
+        {
+            **cells = [InternalCell::default(); INTERNAL_NODE_MAX_CELLS];
+            for (cell, (&child, &key)) in cells.iter_mut().zip(children.iter().zip(keys)) {
+                *cell = InternalCell { child, key };
+            }
+            *num_keys = keys.len() as u32;
+            *right_child = children[keys.len()];
+        }
+    }
+
+    /// Removes and returns the row at `cell_num`, shifting later cells left.
+    pub fn remove(&mut self, cell_num: u32) -> Result<Row, NodeError> {
+        match self {
+            &mut Node::Leaf {
+                ref mut num_cells,
+                ref mut cells,
+                ..
+            } => {
+                if cell_num >= *num_cells {
+                    return Err(NodeError::CellOutOfBounds {
+                        cell_num,
+                        num_cells: *num_cells,
+                    });
+                }
+                let removed = cells.remove(cell_num as usize).0;
+                *num_cells -= 1;
+                Ok(removed)
+            }
+            _ => Err(NodeError::NotALeaf),
+        }
+    }
+
+    /// Panics if a leaf's keys aren't strictly increasing, which would make
+    /// the binary searches over it silently return wrong answers.
+    #[cfg(debug_assertions)]
+    fn assert_sorted(&self) {
+        if let &Node::Leaf {
+            num_cells,
+            ref cells,
+            ..
+        } = self
+        {
+            for pair in cells[..num_cells as usize].windows(2) {
+                assert!(
+                    pair[0].key() < pair[1].key(),
+                    "leaf keys out of order: {} before {}",
+                    pair[0].key(),
+                    pair[1].key()
+                );
+            }
+        }
+    }
+}
+
+/// A leaf cell as the baseline layout stored it: the key, then the row with
+/// its 32-bit id and both strings padded out to their maximum length.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct LegacyCell {
+    key: u32,
+    id: u32,
+    username_len: u8,
+    username: [u8; MAX_UNAME_LENGTH],
+    email_len: u8,
+    #[serde(with = "BigArray")] email: [u8; MAX_EMAIL_LENGTH],
+}
+
+/// How many cells a baseline leaf held, when every one took its maximum
+/// length on disk.
+const LEGACY_LEAF_NODE_MAX_CELLS: usize =
+    (PAGE_SIZE - mem::size_of::<NodeHeader>() - 4) / mem::size_of::<LegacyCell>();
+
+/// The node layout of format version 1, before the metadata page and leaf
+/// sibling pointers. Files that old never grew past a single leaf.
+#[derive(Serialize, Deserialize)]
+enum LegacyNode {
+    Leaf {
+        header: NodeHeader,
+        num_cells: u32,
+        cells: [LegacyCell; LEGACY_LEAF_NODE_MAX_CELLS],
+    },
+}
+
+impl Node {
+    /// Decodes a leaf written in the layout of format version 1. A corrupt
+    /// `num_cells` past the end takes all the cells, and is caught by the
+    /// caller comparing the two.
+    pub fn from_legacy_bytes(bytes: &[u8]) -> Result<Node, bincode::Error> {
+        let LegacyNode::Leaf {
+            header,
+            num_cells,
+            cells,
+        } = deserialize(bytes)?;
+        let cells = cells
+            .iter()
+            .take(num_cells as usize)
+            .map(|cell| {
+                Cell(Row {
+                    id: Key::from(cell.id),
+                    username_len: cell.username_len,
+                    username: cell.username,
+                    email_len: cell.email_len,
+                    email: cell.email,
+                })
+            })
+            .collect();
+        Ok(Node::Leaf {
+            header,
+            num_cells,
+            next_leaf: 0,
+            cells,
+        })
+    }
+
+    /// Encodes a leaf in the layout of format version 1, for building old
+    /// files to test against.
+    #[cfg(test)]
+    pub fn to_legacy_bytes(&self) -> Vec<u8> {
+        let (header, num_cells, cells) = match self {
+            &Node::Leaf {
+                ref header,
+                num_cells,
+                ref cells,
+                ..
+            } => (header, num_cells, cells),
+            _ => panic!("version 1 files only held a leaf"),
+        };
+        let mut legacy = [LegacyCell::from(Row::default()); LEGACY_LEAF_NODE_MAX_CELLS];
+        for (legacy, cell) in legacy.iter_mut().zip(cells) {
+            *legacy = cell.0.into();
+        }
+        let node = LegacyNode::Leaf {
+            header: NodeHeader {
+                is_root: header.is_root,
+                parent_ptr: header.parent_ptr,
+            },
+            num_cells,
+            cells: legacy,
+        };
+        serialize(&node, Infinite).expect("serializing a node into memory cannot fail")
+    }
+}
+
+#[cfg(test)]
+impl From<Row> for LegacyCell {
+    fn from(row: Row) -> Self {
+        // Wider only with `wide-keys`, which version 1 predates.
+        let id = row.id as u32;
+        LegacyCell {
+            key: id,
+            id,
+            username_len: row.username_len,
+            username: row.username,
+            email_len: row.email_len,
+            email: row.email,
         }
     }
 }
@@ -138,12 +762,387 @@ impl fmt::Debug for Node {
                 ..
             } => {
                 writeln!(f, "leaf (size {})", num_cells)?;
-                for (i, &Cell(key, ..)) in cells[..num_cells as usize].iter().enumerate() {
-                    writeln!(f, "  - {} : {}", i, key)?;
+                for (i, cell) in cells[..num_cells as usize].iter().enumerate() {
+                    writeln!(f, "  - {} : {}", i, cell.key())?;
                 }
                 Ok(())
             },
-            _ => Ok(())
+            &Node::Internal {
+                num_keys,
+                right_child,
+                ref cells,
+                ..
+            } => {
+                writeln!(f, "internal (size {})", num_keys)?;
+                for cell in &cells[..num_keys as usize] {
+                    writeln!(f, "  - child {}", cell.child)?;
+                    writeln!(f, "  - key {}", cell.key)?;
+                }
+                writeln!(f, "  - child {}", right_child)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(node: &Node) -> Vec<Key> {
+        match node {
+            &Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => cells[..num_cells as usize].iter().map(|c| c.key()).collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// A full leaf holding the even keys 2, 4, 6, ... in rows of one size.
+    fn full_leaf() -> Node {
+        let mut node = Node::create_leaf();
+        for i in 0.. {
+            let id = 2 * (i as Key + 1);
+            match node.insert(i, &Row::new(id, b"user", b"user@example.com")) {
+                Ok(()) => {}
+                Err(NodeError::Full) => return node,
+                Err(e) => panic!("{:?}", e),
+            }
+        }
+        unreachable!()
+    }
+
+    /// How many cells the left half of `full_leaf()` keeps when it splits.
+    fn left_split_count() -> usize {
+        (keys(&full_leaf()).len() + 2) / 2
+    }
+
+    #[test]
+    fn row_bytes_round_trip() {
+        let email = [b'e'; MAX_EMAIL_LENGTH];
+        let row = Row::new(42, b"user42", &email);
+        let bytes = row.to_bytes();
+        let expected_len = mem::size_of::<Key>() + 1 + 6 + 1 + MAX_EMAIL_LENGTH;
+        assert_eq!(bytes.len(), expected_len);
+        assert_eq!(row.encoded_len(), expected_len);
+
+        let decoded = Row::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.to_string(), row.to_string());
+        assert!(Row::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn rows_take_only_the_bytes_they_use() {
+        let row = Row::new(7, b"bob", b"b@x.io");
+        assert_eq!(row.to_bytes().len(), mem::size_of::<Key>() + 1 + 3 + 1 + 6);
+        assert_eq!(Row::from_bytes(&row.to_bytes()).unwrap().to_string(), "(7, bob, b@x.io)");
+
+        let mut bytes = row.to_bytes();
+        bytes[mem::size_of::<Key>()] = MAX_UNAME_LENGTH as u8 + 1;
+        assert!(Row::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn public_row_trims_fields() {
+        let row = Row::new(7, b"bob", b"bob@example.com");
+        assert_eq!(
+            PublicRow::from(row),
+            PublicRow {
+                id: 7,
+                username: "bob".to_string(),
+                email: "bob@example.com".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn insert_shifts_cells_in_order() {
+        let mut node = Node::create_leaf();
+        for &id in &[9, 7, 5, 3, 1] {
+            node.insert(0, &Row::new(id, b"user", b"user@example.com")).unwrap();
+        }
+        node.insert(2, &Row::new(4, b"user", b"user@example.com")).unwrap();
+        node.insert(6, &Row::new(10, b"user", b"user@example.com")).unwrap();
+        match node {
+            Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => {
+                let keys: Vec<Key> = cells[..num_cells as usize].iter().map(|c| c.key()).collect();
+                assert_eq!(keys, vec![1, 3, 4, 5, 7, 9, 10]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "leaf keys out of order: 5 before 2")]
+    fn insert_catches_misplaced_key() {
+        let mut node = Node::create_leaf();
+        node.insert(0, &Row::new(5, b"user", b"user@example.com")).unwrap();
+        node.insert(1, &Row::new(2, b"user", b"user@example.com")).unwrap();
+    }
+
+    #[test]
+    fn insert_rejects_cell_past_end() {
+        let mut node = Node::create_leaf();
+        let row = Row::new(1, b"user1", b"person1@example.com");
+        node.insert(0, &row).unwrap();
+        assert_eq!(
+            node.insert(2, &row),
+            Err(NodeError::CellOutOfBounds {
+                cell_num: 2,
+                num_cells: 1,
+            })
+        );
+        let mut internal = Node::create_internal(1, 10, 2);
+        assert_eq!(internal.insert(0, &row), Err(NodeError::NotALeaf));
+        match node {
+            Node::Leaf { num_cells, .. } => assert_eq!(num_cells, 1),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn remove_shifts_cells_and_shrinks() {
+        let mut node = Node::create_leaf();
+        for &id in &[4, 3, 2, 1] {
+            node.insert(0, &Row::new(id, b"user", b"user@example.com")).unwrap();
+        }
+        assert_eq!(node.remove(1).unwrap().id, 2);
+        assert_eq!(
+            node.remove(3).map(|row| row.id),
+            Err(NodeError::CellOutOfBounds {
+                cell_num: 3,
+                num_cells: 3,
+            })
+        );
+        let mut internal = Node::create_internal(1, 10, 2);
+        assert_eq!(internal.remove(0).map(|row| row.id), Err(NodeError::NotALeaf));
+        match node {
+            Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => {
+                let keys: Vec<Key> = cells[..num_cells as usize].iter().map(|c| c.key()).collect();
+                assert_eq!(keys, vec![1, 3, 4]);
+                assert_eq!(cells.len(), 3);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn insert_into_full_leaf_asks_for_split() {
+        let mut node = full_leaf();
+        let row = Row::new(1, b"user", b"user@example.com");
+        let num_keys = keys(&node).len();
+        assert_eq!(node.insert(0, &row), Err(NodeError::Full));
+        assert_eq!(keys(&node).len(), num_keys);
+        assert!(node.encoded_len() <= NODE_SPACE);
+        assert!(node.encoded_len() + row.encoded_len() > NODE_SPACE);
+    }
+
+    #[test]
+    fn short_rows_pack_more_cells_per_leaf() {
+        let mut node = Node::create_leaf();
+        let mut id = 0;
+        while node.insert(id, &Row::new(id as Key, b"a", b"a@b")).is_ok() {
+            id += 1;
+        }
+        assert!(keys(&node).len() > 4 * LEGACY_LEAF_NODE_MAX_CELLS);
+    }
+
+    #[test]
+    fn replace_grows_a_row_while_it_fits() {
+        let mut node = full_leaf();
+        let short = Row::new(2, b"u", b"u@example.com");
+        node.replace(0, &short).unwrap();
+        let long = Row::new(2, b"user", &[b'e'; 60]);
+        assert_eq!(node.replace(0, &long), Err(NodeError::Full));
+        node.replace(0, &Row::new(2, b"user", b"user@example.com")).unwrap();
+        assert!(node.encoded_len() <= NODE_SPACE);
+        assert_eq!(
+            node.replace(1000, &short),
+            Err(NodeError::CellOutOfBounds {
+                cell_num: 1000,
+                num_cells: keys(&node).len() as u32,
+            })
+        );
+        let mut internal = Node::create_internal(1, 10, 2);
+        assert_eq!(internal.replace(0, &short), Err(NodeError::NotALeaf));
+    }
+
+    #[test]
+    fn split_and_insert_divides_cells_evenly() {
+        let mut node = full_leaf();
+        let mut expected = keys(&node);
+        let row = Row::new(3, b"user", b"user@example.com");
+        let (split_key, right) = node.split_and_insert(1, &row).unwrap();
+        expected.insert(1, 3);
+
+        let (left_keys, right_keys) = (keys(&node), keys(&right));
+        assert_eq!(left_keys.len(), left_split_count());
+        assert!(left_keys.len() - right_keys.len() <= 1);
+        assert_eq!([&left_keys[..], &right_keys[..]].concat(), expected);
+        assert_eq!(split_key, left_keys[left_keys.len() - 1]);
+        match node {
+            Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => assert_eq!(cells.len(), num_cells as usize),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn split_and_insert_divides_bytes_not_cells() {
+        let long_email = [b'e'; MAX_EMAIL_LENGTH];
+        let mut node = Node::create_leaf();
+        // A few long rows at the front, then short ones until the leaf is full.
+        for id in 0..4 {
+            node.insert(id, &Row::new(id as Key, b"user", &long_email)).unwrap();
+        }
+        let mut id = 4;
+        while node.insert(id, &Row::new(id as Key, b"u", b"u@x")).is_ok() {
+            id += 1;
+        }
+        let row = Row::new(id as Key, b"u", b"u@x");
+        let (_, right) = node.split_and_insert(id, &row).unwrap();
+        assert!(keys(&node).len() < keys(&right).len());
+        assert!(node.encoded_len() <= NODE_SPACE && right.encoded_len() <= NODE_SPACE);
+    }
+
+    #[test]
+    fn split_and_insert_on_the_boundary() {
+        let boundary = left_split_count() as u32;
+
+        // Lands just after the split point: first cell of the right node.
+        let mut node = full_leaf();
+        let key = 2 * boundary as Key + 1;
+        let row = Row::new(key, b"user", b"user@example.com");
+        let (split_key, right) = node.split_and_insert(boundary, &row).unwrap();
+        assert_eq!(keys(&right)[0], key);
+        assert_eq!(split_key, 2 * boundary as Key);
+
+        // Lands just before it: becomes the split key itself.
+        let mut node = full_leaf();
+        let key = 2 * boundary as Key - 1;
+        let row = Row::new(key, b"user", b"user@example.com");
+        let (split_key, right) = node.split_and_insert(boundary - 1, &row).unwrap();
+        assert_eq!(split_key, key);
+        assert_eq!(keys(&node).last(), Some(&key));
+        assert_eq!(keys(&right)[0], 2 * boundary as Key);
+    }
+
+    #[test]
+    fn split_and_insert_new_maximum() {
+        let mut node = full_leaf();
+        let max = keys(&node).len() as u32;
+        let left_count = left_split_count();
+        let row = Row::new(10_000, b"user", b"user@example.com");
+        let (split_key, right) = node.split_and_insert(max, &row).unwrap();
+        assert_eq!(keys(&right).last(), Some(&10_000));
+        assert_eq!(keys(&right).len(), max as usize + 1 - left_count);
+        assert_eq!(split_key, 2 * left_count as Key);
+        let row = Row::new(20_000, b"user", b"user@example.com");
+        assert_eq!(
+            node.split_and_insert(max + 5, &row).map(|(key, _)| key),
+            Err(NodeError::CellOutOfBounds {
+                cell_num: max + 5,
+                num_cells: left_count as u32,
+            })
+        );
+        let mut internal = Node::create_internal(1, 10, 2);
+        assert_eq!(
+            internal.split_and_insert(0, &row).map(|(key, _)| key),
+            Err(NodeError::NotALeaf)
+        );
+    }
+
+    #[test]
+    fn internal_node_routes_keys_to_children() {
+        let node = Node::create_internal(5, 10, 6);
+        assert_eq!(node.children(), vec![5, 6]);
+        assert_eq!(node.keys(), vec![10]);
+        assert_eq!(node.child_for(0), 5);
+        assert_eq!(node.child_for(10), 5);
+        assert_eq!(node.child_for(11), 6);
+        assert_eq!(node.child_index_for(11), 1);
+    }
+
+    #[test]
+    fn insert_child_and_split() {
+        let mut node = Node::create_internal(1, 10, 2);
+        node.insert_child(2, 20, 3).unwrap();
+        node.insert_child(1, 5, 4).unwrap();
+        assert_eq!(node.children(), vec![1, 4, 2, 3]);
+        assert_eq!(node.keys(), vec![5, 10, 20]);
+        assert_eq!(node.insert_child(3, 30, 5), Err(NodeError::Full));
+        assert_eq!(node.insert_child(9, 30, 5), Err(NodeError::NoSuchChild(9)));
+
+        node.header_mut().parent_ptr = 7;
+        let (split_key, right) = node.split_and_insert_child(3, 30, 5).unwrap();
+        assert_eq!(split_key, 20);
+        assert_eq!((node.children(), node.keys()), (vec![1, 4, 2], vec![5, 10]));
+        assert_eq!((right.children(), right.keys()), (vec![3, 5], vec![30]));
+        assert_eq!(right.header().parent_ptr, 7);
+    }
+
+    #[test]
+    fn remove_child_drops_a_neighbouring_key() {
+        let mut node = Node::create_internal(1, 10, 2);
+        node.insert_child(2, 20, 3).unwrap();
+        assert_eq!(node.remove_child(9), Err(NodeError::NoSuchChild(9)));
+        assert_eq!(node.remove_child(2), Ok(2));
+        assert_eq!((node.children(), node.keys()), (vec![1, 3], vec![10]));
+        assert_eq!(node.remove_child(3), Ok(1));
+        assert_eq!((node.children(), node.keys()), (vec![1], vec![]));
+        assert_eq!(node.remove_child(1), Ok(0));
+    }
+
+    #[test]
+    fn split_and_insert_passes_on_next_leaf() {
+        let mut node = full_leaf();
+        if let Node::Leaf {
+            ref mut next_leaf, ..
+        } = node
+        {
+            *next_leaf = 9;
+        }
+        let row = Row::new(1, b"user", b"user@example.com");
+        let (_, right) = node.split_and_insert(0, &row).unwrap();
+        match *right {
+            Node::Leaf { next_leaf, .. } => assert_eq!(next_leaf, 9),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn legacy_leaves_decode_into_the_current_layout() {
+        let mut node = Node::create_leaf();
+        node.insert(0, &Row::new(7, b"user", b"user@example.com")).unwrap();
+        node.insert(1, &Row::new(9, b"user", b"user@example.com")).unwrap();
+        let bytes = node.to_legacy_bytes();
+        let legacy_cell_len = 4 + 4 + 1 + MAX_UNAME_LENGTH + 1 + MAX_EMAIL_LENGTH;
+        assert_eq!(bytes.len(), 4 + 5 + 4 + LEGACY_LEAF_NODE_MAX_CELLS * legacy_cell_len);
+        match Node::from_legacy_bytes(&bytes).unwrap() {
+            Node::Leaf {
+                num_cells,
+                next_leaf,
+                ref cells,
+                ..
+            } => {
+                assert_eq!((num_cells, cells.len(), next_leaf), (2, 2, 0));
+                assert_eq!(cells[1].0.to_string(), "(9, user, user@example.com)");
+            }
+            _ => unreachable!(),
         }
     }
 }
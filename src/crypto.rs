@@ -0,0 +1,144 @@
+//! Optional at-rest page encryption.
+//!
+//! Pages are XORed with a ChaCha20 keystream whose nonce is the page number,
+//! so each page can be read and written independently. The key is stretched
+//! from a passphrase and a per-file salt. This is a learning exercise, not a
+//! vetted cryptosystem: there is no authentication of page contents, and the
+//! key stretching is a simple iterated ChaCha20 rather than a real KDF.
+
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: &[u8; 8] = b"DBTUTENC";
+pub const SALT_LEN: usize = 16;
+pub const TOKEN_LEN: usize = 16;
+/// Bytes taken by the magic, salt and verification token at the start of
+/// an encrypted file. Pages follow immediately after.
+pub const HEADER_SIZE: usize = 8 + SALT_LEN + TOKEN_LEN;
+
+const STRETCH_ROUNDS: u32 = 4096;
+/// Nonce reserved for the verification token; never used for a page.
+const TOKEN_NONCE: u64 = u64::MAX;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function (RFC 7539) with a 64-bit nonce.
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: u64) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[0] = 0x6170_7865;
+    state[1] = 0x3320_646e;
+    state[2] = 0x7962_2d32;
+    state[3] = 0x6b20_6574;
+    state[4..12].copy_from_slice(key);
+    state[12] = counter;
+    state[13] = 0;
+    state[14] = nonce as u32;
+    state[15] = (nonce >> 32) as u32;
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        let word = working[i].wrapping_add(state[i]);
+        for j in 0..4 {
+            out[i * 4 + j] = (word >> (8 * j)) as u8;
+        }
+    }
+    out
+}
+
+fn words_from_bytes(bytes: &[u8]) -> [u32; 8] {
+    let mut words = [0u32; 8];
+    for (i, word) in words.iter_mut().enumerate() {
+        for j in 0..4 {
+            *word |= u32::from(bytes[i * 4 + j]) << (8 * j);
+        }
+    }
+    words
+}
+
+/// A fresh salt for a new encrypted file. Not cryptographically random, but
+/// distinct per file, which is all a salt has to be.
+pub fn new_salt() -> [u8; SALT_LEN] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seed = [
+        now.as_secs() as u32,
+        (now.as_secs() >> 32) as u32,
+        now.subsec_nanos(),
+        process::id(),
+        0,
+        0,
+        0,
+        0,
+    ];
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&chacha20_block(&seed, 0, 0)[..SALT_LEN]);
+    salt
+}
+
+pub struct Cipher {
+    key: [u32; 8],
+}
+
+impl Cipher {
+    /// Stretches `passphrase` with `salt` into a page key. Each 32-byte
+    /// chunk of the passphrase is mixed into the key so far and run through
+    /// the block function, so chunks can't cancel or trade places, and the
+    /// passphrase length goes in as the nonce so trailing zero bytes count.
+    pub fn derive(passphrase: &[u8], salt: &[u8; SALT_LEN]) -> Self {
+        let mut key_bytes = [0u8; 32];
+        key_bytes[..SALT_LEN].copy_from_slice(salt);
+        let mut key = words_from_bytes(&key_bytes);
+        for chunk in passphrase.chunks(32) {
+            for (i, &b) in chunk.iter().enumerate() {
+                key_bytes[i] ^= b;
+            }
+            let block = chacha20_block(&words_from_bytes(&key_bytes), 0, passphrase.len() as u64);
+            key_bytes.copy_from_slice(&block[..32]);
+            key = words_from_bytes(&key_bytes);
+        }
+        for round in 0..STRETCH_ROUNDS {
+            key = words_from_bytes(&chacha20_block(&key, round, 0)[..32]);
+        }
+        Cipher { key }
+    }
+
+    /// Encrypts or decrypts (the two are the same XOR) `data` as page `page_num`.
+    pub fn apply(&self, page_num: u64, data: &mut [u8]) {
+        for (counter, chunk) in data.chunks_mut(64).enumerate() {
+            let keystream = chacha20_block(&self.key, counter as u32, page_num);
+            for (b, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *b ^= *k;
+            }
+        }
+    }
+
+    /// A value stored in the file header so a wrong key is caught on open
+    /// instead of decrypting pages into garbage.
+    pub fn token(&self) -> [u8; TOKEN_LEN] {
+        let mut token = [0u8; TOKEN_LEN];
+        token.copy_from_slice(&chacha20_block(&self.key, 0, TOKEN_NONCE)[..TOKEN_LEN]);
+        token
+    }
+}
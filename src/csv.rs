@@ -0,0 +1,283 @@
+//! CSV import and export for the fixed `id,username,email` schema.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use btree::Row;
+use super::{db_flush, import_row, parse_fields, Scan, Table};
+
+pub const HEADER: &str = "id,username,email";
+
+/// Rows loaded between flushes during an import, so a large import doesn't
+/// hold all of its work in memory until the database is closed.
+const FLUSH_EVERY_ROWS: usize = 1000;
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    HeaderMismatch(String),
+}
+
+impl From<io::Error> for ImportError {
+    fn from(e: io::Error) -> Self {
+        ImportError::Io(e)
+    }
+}
+
+#[derive(Default)]
+pub struct ImportSummary {
+    pub loaded: usize,
+    /// Records whose id was already in the table.
+    pub duplicates: usize,
+    /// Records that don't fit the schema or fail `insert`'s checks.
+    pub invalid: usize,
+}
+
+
+/// Splits one record into its fields, undoing RFC 4180 quoting: a field
+/// wrapped in double quotes may hold commas and line breaks, and `""` inside
+/// it stands for one quote. `None` if a quoted field is never closed.
+fn split_fields(record: &str) -> Option<Vec<String>> {
+    let mut fields = vec![String::new()];
+    let mut in_quotes = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().unwrap();
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(String::new()),
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return None;
+    }
+    Some(fields)
+}
+
+/// Parses one `id,username,email` record, or `None` if it doesn't fit the
+/// schema. The fields are checked the same way `insert` checks them.
+fn parse_record(record: &str) -> Option<Row> {
+    match &split_fields(record)?[..] {
+        [id, username, email] => {
+            let (username, email) = (username.as_bytes(), email.as_bytes());
+            let id = parse_fields(id.trim(), username, email).ok()?;
+            Some(Row::new(id, username, email))
+        }
+        _ => None,
+    }
+}
+
+/// Reads the next record from `reader` without its line ending, or `None`
+/// at the end of the file. A record runs on past a line break that falls
+/// inside a quoted field.
+fn read_record<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut record = String::new();
+    while reader.read_line(&mut record)? > 0 {
+        if record.matches('"').count().is_multiple_of(2) {
+            break;
+        }
+    }
+    if record.is_empty() {
+        return Ok(None);
+    }
+    if record.ends_with('\n') {
+        record.pop();
+        if record.ends_with('\r') {
+            record.pop();
+        }
+    }
+    Ok(Some(record))
+}
+
+/// Appends `field` to `out`, quoted if it holds a comma, a double quote or
+/// a line break.
+fn push_field(out: &mut Vec<u8>, field: &[u8]) {
+    let needs_quotes = field
+        .iter()
+        .any(|&b| b == b',' || b == b'"' || b == b'\n' || b == b'\r');
+    if !needs_quotes {
+        out.extend_from_slice(field);
+        return;
+    }
+    out.push(b'"');
+    for &b in field {
+        if b == b'"' {
+            out.push(b'"');
+        }
+        out.push(b);
+    }
+    out.push(b'"');
+}
+
+/// Encodes `row` as one record, ending in a line break.
+fn format_record(row: &Row) -> Vec<u8> {
+    let mut line = row.id.to_string().into_bytes();
+    line.push(b',');
+    push_field(&mut line, row.username());
+    line.push(b',');
+    push_field(&mut line, row.email());
+    line.push(b'\n');
+    line
+}
+
+/// Writes the header and then every row of `table`, in id order, to
+/// `filename`, in the format `import` reads. Returns how many rows were
+/// written.
+pub fn export(table: &mut Table, filename: &str) -> io::Result<usize> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    writeln!(out, "{}", HEADER)?;
+    let mut written = 0;
+    let mut result = Ok(());
+    table.select_stream(|row| {
+        result = out.write_all(&format_record(row));
+        if result.is_ok() {
+            written += 1;
+            Scan::Continue
+        } else {
+            Scan::Stop
+        }
+    })?;
+    result?;
+    out.flush()?;
+    Ok(written)
+}
+
+/// Loads every record of `filename` into `table`. The file must start with
+/// the `id,username,email` header; records that don't parse or collide with
+/// an existing id are counted rather than aborting the import.
+/// The file is read a record at a time, and the table is flushed as it goes.
+pub fn import(table: &mut Table, filename: &str) -> Result<ImportSummary, ImportError> {
+    import_flushing_every(table, filename, FLUSH_EVERY_ROWS)
+}
+
+fn import_flushing_every(
+    table: &mut Table,
+    filename: &str,
+    flush_every: usize,
+) -> Result<ImportSummary, ImportError> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    let header = read_record(&mut reader)?.unwrap_or_default();
+    if header.trim() != HEADER {
+        return Err(ImportError::HeaderMismatch(header));
+    }
+
+    let mut summary = ImportSummary::default();
+    while let Some(record) = read_record(&mut reader)? {
+        if record.trim().is_empty() {
+            continue;
+        }
+        if import_row(table, &mut summary, parse_record(&record))?
+            && summary.loaded.is_multiple_of(flush_every)
+        {
+            db_flush(table)?;
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::db_open;
+    use btree::Key;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn import_flushes_periodically() {
+        let dir = env::temp_dir();
+        let db_path = dir.join("db_tutorial_import_flush.db");
+        let csv_path = dir.join("db_tutorial_import_flush.csv");
+        let db_path = db_path.to_str().unwrap();
+        let csv_path = csv_path.to_str().unwrap();
+        let mut csv = HEADER.to_string();
+        for id in 1..5001 {
+            csv.push_str(&format!("\n{},user{},person{}@example.com", id, id, id));
+        }
+        fs::write(csv_path, csv).unwrap();
+        let _ = fs::remove_file(db_path);
+
+        let mut table = db_open(db_path).unwrap();
+        table.pager.cache_size = 8;
+        let summary = import_flushing_every(&mut table, csv_path, 2000).unwrap();
+        assert_eq!((summary.loaded, summary.duplicates, summary.invalid), (5000, 0, 0));
+        // The table spans many more pages than were ever held at once.
+        assert!(table.pager.num_pages > 8 * 4);
+        assert!(table.pager.resident_pages() <= 8);
+
+        // Without closing `table`, the first 4000 rows are already on disk,
+        // along with any later ones whose pages were evicted since.
+        let mut on_disk = db_open(db_path).unwrap();
+        let mut ids = Vec::new();
+        on_disk.select_stream(|row| {
+            ids.push(row.id);
+            ::Scan::Continue
+        }).unwrap();
+        assert!(ids.len() >= 4000);
+        assert_eq!(ids, (1..ids.len() as Key + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parse_record_checks_fields_like_insert() {
+        assert!(parse_record("1,user,user@example.com").is_some());
+        assert!(parse_record("-1,user,user@example.com").is_none());
+        assert!(parse_record(&format!("1,{},user@example.com", "a".repeat(33))).is_none());
+        assert!(parse_record("1,user").is_none());
+    }
+
+    #[test]
+    fn parse_record_reads_quoted_fields() {
+        let row = parse_record("1,\"smith, \"\"jr\"\"\",\"a\nb@example.com\"").unwrap();
+        assert_eq!(row.username(), b"smith, \"jr\"");
+        assert_eq!(row.email(), b"a\nb@example.com");
+        assert!(parse_record("1,\"user,user@example.com").is_none());
+    }
+
+    #[test]
+    fn export_writes_rows_in_id_order_that_import_reads_back() {
+        let dir = env::temp_dir();
+        let db_path = dir.join("db_tutorial_export.db");
+        let csv_path = dir.join("db_tutorial_export.csv");
+        let db_path = db_path.to_str().unwrap();
+        let csv_path = csv_path.to_str().unwrap();
+        let _ = fs::remove_file(db_path);
+
+        let mut table = db_open(db_path).unwrap();
+        for &(id, username) in &[(3, "carol"), (1, "alice"), (2, "bob,jr")] {
+            let email = format!("{}@example.com", username);
+            table.insert(&Row::new(id, username.as_bytes(), email.as_bytes())).unwrap();
+        }
+        table.insert(&Row::new(4, b"dan", b"\"dan\"\r\n@example.com")).unwrap();
+        assert_eq!(export(&mut table, csv_path).unwrap(), 4);
+        assert_eq!(
+            fs::read_to_string(csv_path).unwrap(),
+            "id,username,email\n1,alice,alice@example.com\n2,\"bob,jr\",\"bob,jr@example.com\"\n\
+             3,carol,carol@example.com\n4,dan,\"\"\"dan\"\"\r\n@example.com\"\n"
+        );
+
+        let expected = {
+            let mut rows = Vec::new();
+            table.select_stream(|row| {
+                rows.push(row.to_string());
+                ::Scan::Continue
+            }).unwrap();
+            rows
+        };
+        fs::remove_file(db_path).unwrap();
+        let mut table = db_open(db_path).unwrap();
+        let summary = import(&mut table, csv_path).unwrap();
+        assert_eq!((summary.loaded, summary.duplicates, summary.invalid), (4, 0, 0));
+        let mut rows = Vec::new();
+        table.select_stream(|row| {
+            rows.push(row.to_string());
+            ::Scan::Continue
+        }).unwrap();
+        assert_eq!(rows, expected);
+    }
+}
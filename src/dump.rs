@@ -0,0 +1,117 @@
+//! A dump format that restores exactly what was dumped.
+//!
+//! Each row is one line: `insert`, then the id, username and email, all
+//! separated by tabs. Tabs, newlines and backslashes inside a field are
+//! backslash-escaped, so fields can hold anything, including spaces that the
+//! interactive parser would split on.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use btree::{Key, Row};
+use csv::{self, ImportError, ImportSummary};
+use super::{import_row, Scan, Table, MAX_EMAIL_LENGTH, MAX_UNAME_LENGTH};
+
+fn escape(field: &[u8], out: &mut Vec<u8>) {
+    for &b in field {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(b),
+        }
+    }
+}
+
+fn unescape(field: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(field.len());
+    let mut bytes = field.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'\\' => b'\\',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+/// Encodes `row` as one dump line, without the trailing newline.
+fn format_record(row: &Row) -> Vec<u8> {
+    let mut line = format!("insert\t{}\t", row.id).into_bytes();
+    escape(row.username(), &mut line);
+    line.push(b'\t');
+    escape(row.email(), &mut line);
+    line
+}
+
+/// Parses one dump line, or `None` if it isn't one `format_record` writes.
+fn parse_record(line: &str) -> Option<Row> {
+    let mut fields = line.split('\t');
+    match (fields.next(), fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some("insert"), Some(id), Some(username), Some(email), None) => {
+            let id = id.parse::<Key>().ok()?;
+            let username = unescape(username)?;
+            let email = unescape(email)?;
+            if username.len() > MAX_UNAME_LENGTH || email.len() > MAX_EMAIL_LENGTH {
+                return None;
+            }
+            Some(Row::new(id, &username, &email))
+        }
+        _ => None,
+    }
+}
+
+/// Writes every row of `table` to `filename`, returning how many were written.
+pub fn dump(table: &mut Table, filename: &str) -> io::Result<usize> {
+    let mut out = BufWriter::new(File::create(filename)?);
+    let mut written = 0;
+    let mut result = Ok(());
+    table.select_stream(|row| {
+        let mut line = format_record(row);
+        line.push(b'\n');
+        result = out.write_all(&line);
+        if result.is_ok() {
+            written += 1;
+            Scan::Continue
+        } else {
+            Scan::Stop
+        }
+    })?;
+    result?;
+    out.flush()?;
+    Ok(written)
+}
+
+/// Loads either a file written by `dump` or a CSV file into `table`,
+/// telling them apart by whether the first line is the CSV header.
+pub fn load(table: &mut Table, filename: &str) -> Result<ImportSummary, ImportError> {
+    let mut first_line = String::new();
+    BufReader::new(File::open(filename)?).read_line(&mut first_line)?;
+    if first_line.trim() == csv::HEADER {
+        csv::import(table, filename)
+    } else {
+        Ok(restore(table, filename)?)
+    }
+}
+
+/// Loads a file written by `dump` into `table`. Lines that don't parse or
+/// collide with an existing id are counted rather than aborting the load.
+pub fn restore(table: &mut Table, filename: &str) -> io::Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    for line in BufReader::new(File::open(filename)?).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        import_row(table, &mut summary, parse_record(&line))?;
+    }
+    Ok(summary)
+}
@@ -0,0 +1,184 @@
+//! Deterministic insert/verify harness.
+//!
+//! A seed drives a reproducible sequence of operations that is applied both to
+//! a real table and to an in-memory `BTreeMap` oracle. After every operation a
+//! full scan of the table must match the oracle exactly. Rows are of the
+//! maximum length, so a leaf holds only a dozen or so. In the first half of a
+//! run inserts outnumber deletes three to one, so the table grows and splits;
+//! in the second half deletes outnumber inserts seven to one, so it shrinks
+//! and leaves are emptied and freed.
+
+use std::cmp;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use btree::{Key, Row};
+use super::{
+    db_open, ExecuteError, PagerError, Resolution, Scan, Table, MAX_EMAIL_LENGTH, MAX_UNAME_LENGTH,
+};
+
+/// Keys are drawn from a range small enough that duplicates come up
+/// regularly, but that spans several leaves.
+const KEY_SPACE: u64 = 64;
+
+/// xorshift64*: tiny, dependency-free and good enough to shake out bugs.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Insert(Key),
+    Delete(Key),
+}
+
+/// What a run that matched the oracle did to the tree.
+pub struct Summary {
+    /// The most levels the tree had at any point.
+    pub max_height: u32,
+    /// Pages freed by deletes that emptied a leaf.
+    pub pages_freed: usize,
+}
+
+pub struct Failure {
+    pub ops: Vec<Op>,
+    pub reason: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "mismatch after {} operations: {}", self.ops.len(), self.reason)?;
+        for op in &self.ops {
+            writeln!(f, "  {:?}", op)?;
+        }
+        Ok(())
+    }
+}
+
+fn scan(table: &mut Table) -> Result<Vec<String>, PagerError> {
+    let mut rows = Vec::new();
+    table.select_stream(|row| {
+        rows.push(row.to_string());
+        Scan::Continue
+    })?;
+    Ok(rows)
+}
+
+fn make_row(id: Key) -> Row {
+    let mut username = format!("user{}", id).into_bytes();
+    username.resize(MAX_UNAME_LENGTH, b'u');
+    let mut email = format!("person{}@example.com", id).into_bytes();
+    email.resize(MAX_EMAIL_LENGTH, b'e');
+    Row::new(id, &username, &email)
+}
+
+/// Runs `num_ops` seeded operations against a fresh database at `filename`,
+/// returning the failing operation sequence on the first divergence.
+pub fn run(seed: u64, num_ops: usize, filename: &str) -> Result<Summary, Failure> {
+    let _ = ::std::fs::remove_file(filename);
+    let mut table = db_open(filename).map_err(|e| Failure {
+        ops: Vec::new(),
+        reason: format!("could not open {}: {}", filename, e),
+    })?;
+    let mut rng = Rng::new(seed);
+    let mut oracle = BTreeMap::new();
+    let mut ops = Vec::new();
+    let mut summary = Summary {
+        max_height: 1,
+        pages_freed: 0,
+    };
+
+    for i in 0..num_ops {
+        let id = (rng.next() % KEY_SPACE) as Key;
+        let deletes_in_eight = if i < num_ops / 2 { 2 } else { 7 };
+        let op = if rng.next() % 8 < deletes_in_eight {
+            Op::Delete(id)
+        } else {
+            Op::Insert(id)
+        };
+        ops.push(op);
+        let free_before = table.pager.free_pages.len();
+        let (expected, actual) = match op {
+            Op::Insert(id) => {
+                let row = make_row(id);
+                let expected = match oracle.entry(id) {
+                    Entry::Occupied(_) => Err(ExecuteError::DuplicateKey),
+                    Entry::Vacant(slot) => {
+                        slot.insert(row);
+                        Ok(())
+                    }
+                };
+                (expected, table.insert_with(&row, |_, _| Resolution::Error))
+            }
+            Op::Delete(id) => {
+                let expected = match oracle.remove(&id) {
+                    Some(_) => Ok(()),
+                    None => Err(ExecuteError::NoSuchKey),
+                };
+                (expected, table.delete(id).map(|_| ()))
+            }
+        };
+        if actual != expected {
+            return Err(Failure {
+                ops,
+                reason: format!("expected {:?}, got {:?}", expected, actual),
+            });
+        }
+
+        let expected_rows: Vec<String> = oracle.values().map(|row| row.to_string()).collect();
+        let actual_rows = scan(&mut table).map_err(|e| Failure {
+            ops: ops.clone(),
+            reason: format!("scan failed: {}", e),
+        })?;
+        if actual_rows != expected_rows {
+            return Err(Failure {
+                ops,
+                reason: format!("expected rows {:?}, scanned {:?}", expected_rows, actual_rows),
+            });
+        }
+
+        summary.pages_freed += table.pager.free_pages.len().saturating_sub(free_before);
+        let height = table.height().map_err(|e| Failure {
+            ops: ops.clone(),
+            reason: format!("could not measure the tree: {}", e),
+        })?;
+        summary.max_height = cmp::max(summary.max_height, height);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn seeded_runs_match_oracle() {
+        let path = env::temp_dir().join("db_tutorial_fuzz.db");
+        let mut pages_freed = 0;
+        for seed in 0..20 {
+            match run(seed, 200, path.to_str().unwrap()) {
+                Ok(summary) => {
+                    assert!(summary.max_height > 1, "seed {} never split a leaf", seed);
+                    pages_freed += summary.pages_freed;
+                }
+                Err(failure) => panic!("seed {}: {}", seed, failure),
+            }
+        }
+        assert!(pages_freed > 0, "no seed emptied a leaf");
+    }
+}
@@ -0,0 +1,3316 @@
+#![feature(const_size_of)]
+//! A single fixed-schema table of `(id, username, email)` rows, kept in a
+//! B+ tree in one file. `Table::open`, `Table::insert`, `Table::select` and
+//! `Table::close` are the way in for other programs; the `db_tutorial` binary
+//! puts a REPL on top.
+
+extern crate bincode;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+pub mod btree;
+mod crypto;
+pub mod csv;
+pub mod dump;
+pub mod fuzz;
+pub mod meta;
+mod serde_ext;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+
+use bincode::{deserialize, serialize, Infinite};
+
+use btree::{Node, NODE_SPACE, PAGE_SIZE};
+pub use btree::{Key, PublicRow, Row};
+use meta::Meta;
+
+pub const MAX_UNAME_LENGTH: usize = 32;
+pub const MAX_EMAIL_LENGTH: usize = 255;
+
+#[derive(Debug, PartialEq)]
+pub enum PagerError {
+    OutOfBounds { page_num: usize, max_pages: usize },
+    CouldNotRead,
+    CouldNotWrite,
+    /// The tree's pointers lead somewhere they can't, e.g. round a cycle.
+    Corrupt { page_num: usize },
+}
+
+impl fmt::Display for PagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PagerError::OutOfBounds {
+                page_num,
+                max_pages,
+            } => write!(f, "page {} is out of bounds (max {} pages)", page_num, max_pages),
+            PagerError::CouldNotRead => write!(f, "could not read page from file"),
+            PagerError::CouldNotWrite => write!(f, "could not write page to file"),
+            PagerError::Corrupt { page_num } => write!(f, "page {} is corrupt", page_num),
+        }
+    }
+}
+
+impl From<PagerError> for io::Error {
+    fn from(e: PagerError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+pub struct Cursor<'a> {
+    table: &'a mut Table,
+    page_num: u32,
+    cell_num: u32,
+    end_of_table: bool,
+}
+
+impl<'a> Cursor<'a> {
+    /// The row under the cursor, or `None` if the cursor sits past the last
+    /// cell of its leaf, where only default or stale cells live.
+    fn get_value(&mut self) -> Result<Option<&Row>, PagerError> {
+        let page_num = self.page_num as usize;
+        let page = self.table.pager.get_page(page_num)?;
+        Ok(match page {
+            &Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => if self.cell_num < num_cells {
+                Some(&(cells[self.cell_num as usize].0))
+            } else {
+                None
+            },
+            _ => unreachable!("cursors only point into leaves"),
+        })
+    }
+
+    /// Overwrites the row under the cursor with `val`, which has the same id.
+    /// If `val` is longer and its leaf has no room for the difference, the
+    /// leaf is split, after which the cursor may no longer be on `val`.
+    fn set_value(&mut self, val: &Row) -> Result<(), ExecuteError> {
+        let (page_num, cell_num) = (self.page_num, self.cell_num);
+        let page = self.table.pager.get_page_mut(page_num as usize)?;
+        match page.replace(cell_num, val) {
+            Ok(()) => Ok(()),
+            Err(btree::NodeError::Full) => {
+                self.table.split_leaf_and_replace(page_num, cell_num, val)
+            }
+            Err(_) => unreachable!("cursors only point into leaves"),
+        }
+    }
+
+    fn advance(&mut self) -> Result<(), PagerError> {
+        self.cell_num += 1;
+        self.skip_exhausted_leaves()
+    }
+
+    /// If the cursor is past the last cell of its leaf, follows `next_leaf`
+    /// to the first cell of the next leaf that has any, or flags the end of
+    /// the table if there is none.
+    fn skip_exhausted_leaves(&mut self) -> Result<(), PagerError> {
+        loop {
+            let (num_cells, next_leaf) = match self.table.pager.get_page(self.page_num as usize)? {
+                &Node::Leaf {
+                    num_cells,
+                    next_leaf,
+                    ..
+                } => (num_cells, next_leaf),
+                _ => unreachable!("cursors only point into leaves"),
+            };
+            if self.cell_num < num_cells {
+                return Ok(());
+            }
+            if next_leaf == 0 {
+                self.end_of_table = true;
+                return Ok(());
+            }
+            self.page_num = next_leaf;
+            self.cell_num = 0;
+        }
+    }
+
+    /// Steps back to the previous cell, crossing into earlier leaves as
+    /// needed. Returns false if already at the first row.
+    fn retreat(&mut self) -> Result<bool, PagerError> {
+        let (mut page_num, mut cell_num) = (self.page_num, self.cell_num);
+        while cell_num == 0 {
+            page_num = match self.table.previous_leaf(page_num)? {
+                Some(page_num) => page_num,
+                None => return Ok(false),
+            };
+            cell_num = match self.table.pager.get_page(page_num as usize)? {
+                &Node::Leaf { num_cells, .. } => num_cells,
+                _ => unreachable!("previous_leaf always returns a leaf"),
+            };
+        }
+        self.page_num = page_num;
+        self.cell_num = cell_num - 1;
+        self.end_of_table = false;
+        Ok(true)
+    }
+
+    /// Removes the row under the cursor, leaving the cursor on the row that
+    /// followed it. The cursor must be on a row. A leaf other than the root
+    /// that this empties is freed.
+    fn remove(&mut self) -> Result<Row, PagerError> {
+        let page_num = self.page_num;
+        let (removed, num_cells, next_leaf) = {
+            let page = self.table.pager.get_page_mut(page_num as usize)?;
+            let removed = page.remove(self.cell_num).expect("the cursor is on a row");
+            match page {
+                &mut Node::Leaf {
+                    num_cells,
+                    next_leaf,
+                    ..
+                } => (removed, num_cells, next_leaf),
+                _ => unreachable!("cursors only point into leaves"),
+            }
+        };
+        if num_cells == 0 && page_num != self.table.root_page_num {
+            let previous = self.table.free_empty_leaf(page_num)?;
+            if next_leaf == 0 {
+                // That was the last leaf; park past the end of the new last one.
+                self.page_num = previous.unwrap_or(self.table.root_page_num);
+                self.cell_num = match self.table.pager.get_page(self.page_num as usize)? {
+                    &Node::Leaf { num_cells, .. } => num_cells,
+                    _ => unreachable!("previous_leaf always returns a leaf"),
+                };
+                self.end_of_table = true;
+                return Ok(removed);
+            }
+            self.page_num = next_leaf;
+            self.cell_num = 0;
+        }
+        self.skip_exhausted_leaves()?;
+        Ok(removed)
+    }
+
+    /// Inserts `val` at the cursor, which `find` positioned for its id,
+    /// splitting the leaf if it is full. If a row with that id is already
+    /// there, `resolver` gets it and `val` and decides what happens.
+    fn insert_or_resolve<F>(mut self, val: &Row, mut resolver: F) -> Result<(), ExecuteError>
+    where
+        F: FnMut(&Row, &Row) -> Resolution,
+    {
+        let has_room = self.table
+            .pager
+            .get_page(self.page_num as usize)?
+            .has_room_for(val);
+        let existing = self.get_value()?.cloned();
+        match existing {
+            Some(ref existing) if existing.id == val.id => {
+                return match resolver(existing, val) {
+                    Resolution::KeepExisting => Ok(()),
+                    Resolution::UseNew => self.set_value(val),
+                    Resolution::Error => Err(ExecuteError::DuplicateKey),
+                };
+            }
+            _ => {}
+        }
+        if !has_room {
+            let (page_num, cell_num) = (self.page_num, self.cell_num);
+            return self.table.split_leaf_and_insert(page_num, cell_num, val);
+        }
+        Ok(self.insert(val)?)
+    }
+
+    /// Inserts `val` at the cursor, which must be in a leaf with room for it.
+    fn insert(&mut self, val: &Row) -> Result<(), PagerError> {
+        let page_num = self.page_num as usize;
+        let page = self.table.pager.get_page_mut(page_num)?;
+        page.insert(self.cell_num, val).expect("stale cursor");
+        Ok(())
+    }
+}
+
+/// Counts of `get_page`/`get_page_mut` lookups since open or the last reset.
+#[derive(Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Pages dropped from memory to make room.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            100.0 * self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+pub struct Pager {
+    fd: File,
+    file_size: u64,
+    /// The resident pages, by page number.
+    pages: HashMap<usize, Box<Node>>,
+    /// Which resident pages have changed since they were last written.
+    dirty: HashSet<usize>,
+    /// Most pages to keep resident. Past this, the least recently used page
+    /// is written back if need be and dropped, to be read again when next
+    /// wanted.
+    pub cache_size: usize,
+    /// When each resident page was last asked for, as a value of `clock`.
+    last_used: HashMap<usize, u64>,
+    clock: u64,
+    num_pages: usize,
+    /// Page numbers at or past this are refused with `OutOfBounds`.
+    max_pages: usize,
+    /// Pages given up by the tree, to be reused before the file grows. The
+    /// last one is the head of the list kept on disk.
+    free_pages: Vec<u32>,
+    /// Fsync after every write. Much slower for large flushes, since each
+    /// write waits on the disk rather than the OS cache, but nothing written
+    /// is left sitting in the page cache if the machine goes down.
+    pub sync_writes: bool,
+    /// Set when the file is encrypted; pages are decrypted on load and
+    /// encrypted on flush.
+    cipher: Option<crypto::Cipher>,
+    /// Where page 0 starts in the file, after any encryption header.
+    data_offset: u64,
+    pub cache_stats: CacheStats,
+}
+
+impl Pager {
+    fn new(f: File, file_size: u64, cache_size: usize) -> Self {
+        Pager {
+            fd: f,
+            file_size,
+            pages: HashMap::new(),
+            dirty: HashSet::new(),
+            cache_size,
+            last_used: HashMap::new(),
+            clock: 0,
+            // Count a partial last page, so a new page is never put over it.
+            num_pages: file_size as usize / PAGE_SIZE
+                + (!(file_size as usize).is_multiple_of(PAGE_SIZE) as usize),
+            max_pages: TABLE_MAX_PAGES,
+            free_pages: Vec::new(),
+            sync_writes: false,
+            cipher: None,
+            data_offset: 0,
+            cache_stats: CacheStats::default(),
+        }
+    }
+
+    /// Sets up page encryption. A new, empty file gets an encryption header;
+    /// an existing file must already have one, and `passphrase` must match it.
+    fn enable_encryption(&mut self, passphrase: &str) -> Result<(), io::Error> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut header = [0u8; crypto::HEADER_SIZE];
+        let mut salt = [0u8; crypto::SALT_LEN];
+        if self.file_size == 0 {
+            salt = crypto::new_salt();
+            let cipher = crypto::Cipher::derive(passphrase.as_bytes(), &salt);
+            header[..8].copy_from_slice(crypto::MAGIC);
+            header[8..8 + crypto::SALT_LEN].copy_from_slice(&salt);
+            header[8 + crypto::SALT_LEN..].copy_from_slice(&cipher.token());
+            self.fd.seek(io::SeekFrom::Start(0))?;
+            self.fd.write_all(&header)?;
+            self.file_size = header.len() as u64;
+        } else {
+            if !self.is_encrypted()? {
+                return Err(invalid("database is not encrypted"));
+            }
+            self.fd.seek(io::SeekFrom::Start(0))?;
+            self.fd.read_exact(&mut header)?;
+            salt.copy_from_slice(&header[8..8 + crypto::SALT_LEN]);
+        }
+        let cipher = crypto::Cipher::derive(passphrase.as_bytes(), &salt);
+        if cipher.token()[..] != header[8 + crypto::SALT_LEN..] {
+            return Err(invalid("wrong key for encrypted database"));
+        }
+        self.data_offset = crypto::HEADER_SIZE as u64;
+        self.num_pages = self.pages_on_disk();
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+
+    /// Whether the file starts with an encryption header.
+    fn is_encrypted(&mut self) -> Result<bool, io::Error> {
+        if self.file_size < crypto::HEADER_SIZE as u64 {
+            return Ok(false);
+        }
+        let mut magic = [0u8; 8];
+        self.fd.seek(io::SeekFrom::Start(0))?;
+        self.fd.read_exact(&mut magic)?;
+        Ok(&magic == crypto::MAGIC)
+    }
+
+    fn page_offset(&self, page_num: usize) -> u64 {
+        self.data_offset + page_num as u64 * PAGE_SIZE as u64
+    }
+
+    /// Opens `filename`, keeping at most `cache_size` pages in memory.
+    fn open(filename: &str, cache_size: usize) -> Result<Box<Self>, io::Error> {
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(filename)?;
+        let file_size = f.seek(io::SeekFrom::End(0))?;
+        let pager = Box::new(Pager::new(f, file_size, cache_size));
+        Ok(pager)
+    }
+
+    // fn read_into_row<R: Read>(&self, reader: &mut R, row: &mut Row) -> io::Result<()> {
+    //     std::mem::replace(row, deserialize_from(reader, Infinite));
+    //     Ok(())
+    // }
+
+    fn get_page<'a>(&'a mut self, page_num: usize) -> Result<&'a Node, PagerError> {
+        self.load_page(page_num)?;
+        Ok(&self.pages[&page_num])
+    }
+
+    fn get_page_mut<'a>(&'a mut self, page_num: usize) -> Result<&'a mut Node, PagerError> {
+        self.load_page(page_num)?;
+        self.dirty.insert(page_num);
+        Ok(self.pages.get_mut(&page_num).unwrap())
+    }
+
+    /// Makes `page_num` resident, reading or creating it on a cache miss.
+    fn load_page(&mut self, page_num: usize) -> Result<(), PagerError> {
+        if page_num >= self.max_pages {
+            return Err(PagerError::OutOfBounds {
+                page_num,
+                max_pages: self.max_pages,
+            });
+        }
+        self.touch(page_num);
+        if self.pages.contains_key(&page_num) {
+            self.cache_stats.hits += 1;
+            return Ok(());
+        }
+        self.cache_stats.misses += 1;
+        let new_page = self.allocate_page(page_num).map_err(|e| {
+            if e.kind() == io::ErrorKind::InvalidData {
+                PagerError::Corrupt { page_num }
+            } else {
+                PagerError::CouldNotRead
+            }
+        })?;
+        self.pages.insert(page_num, new_page);
+        // A page that isn't in the file yet has to be written even if unchanged.
+        if page_num >= self.pages_on_disk() {
+            self.dirty.insert(page_num);
+        }
+        if page_num >= self.num_pages {
+            self.num_pages = page_num + 1;
+        }
+        self.evict_all_but(page_num)
+    }
+
+    /// Marks `page_num` as the most recently used page.
+    fn touch(&mut self, page_num: usize) {
+        self.clock += 1;
+        self.last_used.insert(page_num, self.clock);
+    }
+
+    /// Drops least recently used pages, writing them back first if they have
+    /// changed, until no more than `cache_size` are resident. `keep`, the
+    /// page the caller is about to use, always stays.
+    fn evict_all_but(&mut self, keep: usize) -> Result<(), PagerError> {
+        while self.pages.len() > cmp::max(self.cache_size, 1) {
+            let victim = {
+                let last_used = &self.last_used;
+                *self.pages
+                    .keys()
+                    .filter(|&&page_num| page_num != keep)
+                    .min_by_key(|&page_num| last_used.get(page_num).cloned().unwrap_or(0))
+                    .expect("more than one page is resident")
+            };
+            self.flush_page(victim).map_err(|_| PagerError::CouldNotWrite)?;
+            self.pages.remove(&victim);
+            self.last_used.remove(&victim);
+            self.cache_stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Number of pages present in the file, counting a partial last page.
+    fn pages_on_disk(&self) -> usize {
+        let data_size = (self.file_size - self.data_offset) as usize;
+        data_size / PAGE_SIZE + (!data_size.is_multiple_of(PAGE_SIZE) as usize)
+    }
+
+    /// Whether `page_num` has ever been created, without loading or creating it.
+    fn page_exists(&self, page_num: usize) -> bool {
+        self.pages.contains_key(&page_num) || page_num < self.pages_on_disk()
+    }
+
+    fn allocate_page(&mut self, page_num: usize) -> io::Result<Box<Node>> {
+        if page_num < self.pages_on_disk() {
+            self.read_page(page_num)
+        } else {
+            Ok(Box::new(Node::create_leaf()))
+        }
+    }
+
+    /// The first page number past every page in use, in memory or on disk.
+    fn get_unused_page_num(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Pages that `add_page` can still hand out, free ones included.
+    fn pages_available(&self) -> usize {
+        self.max_pages.saturating_sub(self.num_pages) + self.free_pages.len()
+    }
+
+    /// Puts `node` on a new page, e.g. the right half of a split, and returns
+    /// the page number. Freed pages are reused before the file grows.
+    fn add_page(&mut self, node: Box<Node>) -> Result<u32, PagerError> {
+        if let Some(page_num) = self.free_pages.pop() {
+            self.pages.insert(page_num as usize, node);
+            self.dirty.insert(page_num as usize);
+            self.touch(page_num as usize);
+            self.evict_all_but(page_num as usize)?;
+            return Ok(page_num);
+        }
+        let page_num = self.get_unused_page_num();
+        if page_num >= self.max_pages {
+            return Err(PagerError::OutOfBounds {
+                page_num,
+                max_pages: self.max_pages,
+            });
+        }
+        self.pages.insert(page_num, node);
+        self.dirty.insert(page_num);
+        self.num_pages = page_num + 1;
+        self.touch(page_num);
+        self.evict_all_but(page_num)?;
+        Ok(page_num as u32)
+    }
+
+    /// Puts `page_num`, no longer part of the tree, on the free list. Its
+    /// contents become an empty leaf whose `next_leaf` is the next free page,
+    /// which is how the list is kept on disk.
+    fn free_page(&mut self, page_num: u32) {
+        let mut node = Node::create_leaf();
+        if let Node::Leaf {
+            ref mut next_leaf, ..
+        } = node
+        {
+            *next_leaf = self.free_list_head();
+        }
+        self.pages.insert(page_num as usize, Box::new(node));
+        self.dirty.insert(page_num as usize);
+        self.free_pages.push(page_num);
+    }
+
+    /// Drops free pages from the end of the file and shrinks it to match,
+    /// relinking the free list around them. Preallocated pages are only
+    /// dropped along with freed ones. Returns how many pages went.
+    fn truncate_free_tail(&mut self) -> Result<usize, PagerError> {
+        let num_pages = self.num_pages;
+        while self.num_pages > 0 {
+            let last = self.num_pages - 1;
+            match self.free_pages.iter().position(|&p| p as usize == last) {
+                Some(i) => self.free_pages.remove(i),
+                None => break,
+            };
+            self.pages.remove(&last);
+            self.dirty.remove(&last);
+            self.last_used.remove(&last);
+            self.num_pages = last;
+        }
+        let truncated = num_pages - self.num_pages;
+        if truncated == 0 {
+            return Ok(0);
+        }
+        for i in 0..self.free_pages.len() {
+            let next = if i == 0 { 0 } else { self.free_pages[i - 1] };
+            if let &mut Node::Leaf {
+                ref mut next_leaf, ..
+            } = self.get_page_mut(self.free_pages[i] as usize)?
+            {
+                *next_leaf = next;
+            }
+        }
+        let len = self.page_offset(self.num_pages);
+        if len < self.file_size {
+            self.fd.set_len(len).map_err(|_| PagerError::CouldNotRead)?;
+            self.file_size = len;
+        }
+        Ok(truncated)
+    }
+
+    /// The first free page, or 0 if there are none.
+    fn free_list_head(&self) -> u32 {
+        self.free_pages.last().cloned().unwrap_or(0)
+    }
+
+    /// Follows the on-disk free list from `head` to fill in `free_pages`.
+    fn load_free_list(&mut self, head: u32) -> Result<(), PagerError> {
+        let mut free_pages = Vec::new();
+        let mut page_num = head;
+        while page_num != 0 {
+            // The list can't be longer than the file, unless it has a cycle.
+            if free_pages.len() >= self.num_pages {
+                return Err(PagerError::Corrupt {
+                    page_num: page_num as usize,
+                });
+            }
+            free_pages.push(page_num);
+            page_num = match self.get_page(page_num as usize)? {
+                &Node::Leaf { next_leaf, .. } => next_leaf,
+                _ => {
+                    return Err(PagerError::Corrupt {
+                        page_num: page_num as usize,
+                    })
+                }
+            };
+        }
+        free_pages.reverse();
+        self.free_pages = free_pages;
+        Ok(())
+    }
+
+    /// Reads and decodes on-disk page `page_num`, failing if it doesn't
+    /// match its checksum, e.g. because a crash cut the write short.
+    fn read_page(&mut self, page_num: usize) -> io::Result<Box<Node>> {
+        self.read_page_with(page_num, |buf| {
+            if !checksum_matches(buf) {
+                let msg = "checksum mismatch".to_string();
+                return Err(Box::new(bincode::ErrorKind::Custom(msg)));
+            }
+            deserialize(&buf[..NODE_SPACE])
+        })
+    }
+
+    /// Reads on-disk page `page_num`, written in the layout of format
+    /// version 1.
+    fn read_legacy_page(&mut self, page_num: usize) -> io::Result<Box<Node>> {
+        self.read_page_with(page_num, |buf| Node::from_legacy_bytes(buf).map(Box::new))
+    }
+
+    fn read_page_with<F>(&mut self, page_num: usize, decode: F) -> io::Result<Box<Node>>
+    where
+        F: FnOnce(&[u8]) -> bincode::Result<Box<Node>>,
+    {
+        let offset = self.page_offset(page_num);
+        self.fd.seek(io::SeekFrom::Start(offset))?;
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        (&mut self.fd).take(PAGE_SIZE as u64).read_to_end(&mut buf)?;
+        if buf.iter().all(|&b| b == 0) {
+            // Preallocated but never written.
+            return Ok(Box::new(Node::create_leaf()));
+        }
+        if let Some(ref cipher) = self.cipher {
+            cipher.apply(page_num as u64, &mut buf);
+        }
+        let node = decode(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match *node {
+            Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } if num_cells as usize != cells.len() =>
+            {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "wrong cell count"))
+            }
+            Node::Internal { num_keys, .. }
+                if num_keys as usize > btree::INTERNAL_NODE_MAX_CELLS =>
+            {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "too many keys"))
+            }
+            _ => Ok(node),
+        }
+    }
+
+    /// Reads page 0, or returns `None` if it isn't a metadata page.
+    fn read_meta(&mut self) -> io::Result<Option<Meta>> {
+        let page_num = meta::META_PAGE_NUM as usize;
+        let offset = self.page_offset(page_num);
+        self.fd.seek(io::SeekFrom::Start(offset))?;
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        (&mut self.fd).take(PAGE_SIZE as u64).read_to_end(&mut buf)?;
+        if let Some(ref cipher) = self.cipher {
+            cipher.apply(page_num as u64, &mut buf);
+        }
+        Ok(Meta::from_bytes(&buf))
+    }
+
+    fn write_meta(&mut self, meta: &Meta) -> io::Result<()> {
+        let page_num = meta::META_PAGE_NUM as usize;
+        let mut buf = meta.to_bytes();
+        if let Some(ref cipher) = self.cipher {
+            cipher.apply(page_num as u64, &mut buf);
+        }
+        self.write_at(page_num, &buf)
+    }
+
+    /// Moves the root leaf of a file written before the metadata page
+    /// existed from page 0 to `root_page_num`. Those files never grew past a
+    /// single leaf, so anything at `root_page_num` is unused preallocation.
+    fn migrate_headerless(&mut self, root_page_num: usize) -> io::Result<()> {
+        let root = self.read_legacy_page(meta::META_PAGE_NUM as usize)?;
+        self.pages.insert(root_page_num, root);
+        self.dirty.insert(root_page_num);
+        self.num_pages = cmp::max(self.num_pages, root_page_num + 1);
+        Ok(())
+    }
+
+    /// Grows the file to hold at least `num_pages` pages up front, so a bulk
+    /// load doesn't keep extending it. The new space reads back as zeros,
+    /// which `allocate_page` treats as empty leaves. `num_pages` (the pages
+    /// actually in use) is left alone.
+    pub fn preallocate(&mut self, num_pages: usize) -> Result<(), PagerError> {
+        if num_pages > self.max_pages {
+            return Err(PagerError::OutOfBounds {
+                page_num: num_pages,
+                max_pages: self.max_pages,
+            });
+        }
+        let len = self.page_offset(num_pages);
+        if len > self.file_size {
+            self.fd.set_len(len).map_err(|_| PagerError::CouldNotRead)?;
+            self.file_size = len;
+        }
+        Ok(())
+    }
+
+    /// Number of pages currently held in memory.
+    pub fn resident_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Approximate bytes taken by the pages held in memory: each `Node`,
+    /// plus the cells it keeps on the heap.
+    pub fn resident_bytes(&self) -> usize {
+        self.pages
+            .values()
+            .map(|node| {
+                let cells_len = match **node {
+                    Node::Leaf { ref cells, .. } => cells.len() * mem::size_of::<btree::Cell>(),
+                    Node::Internal { ref cells, .. } => mem::size_of_val(&**cells),
+                };
+                mem::size_of::<Node>() + cells_len
+            })
+            .sum()
+    }
+
+    /// Serializes (and encrypts, if enabled) page `page_num`, zero-padded to
+    /// a full page and ending in its checksum, if it is resident and has
+    /// changed since it was written. The padding overwrites whatever a longer
+    /// encoding left behind, e.g. when a leaf turns into an internal node.
+    fn encode_page(&self, page_num: usize) -> Result<Option<Vec<u8>>, io::Error> {
+        match self.pages.get(&page_num) {
+            Some(page) if self.dirty.contains(&page_num) => {
+                let mut buf = serialize(&page, Infinite)
+                    .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+                debug_assert!(buf.len() <= NODE_SPACE, "page {} overflows", page_num);
+                buf.resize(NODE_SPACE, 0);
+                let checksum = page_checksum(&buf);
+                buf.extend_from_slice(&checksum.to_le_bytes());
+                if let Some(ref cipher) = self.cipher {
+                    cipher.apply(page_num as u64, &mut buf);
+                }
+                Ok(Some(buf))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn flush_page(&mut self, page_num: usize) -> Result<(), io::Error> {
+        if let Some(buf) = self.encode_page(page_num)? {
+            self.write_at(page_num, &buf)?;
+            self.dirty.remove(&page_num);
+        }
+        Ok(())
+    }
+
+    /// Writes every changed page back in page order, coalescing runs of
+    /// adjacent pages into a single seek and write.
+    fn flush_all(&mut self) -> Result<(), io::Error> {
+        let mut dirty: Vec<usize> = self.dirty.iter().cloned().collect();
+        dirty.sort();
+        let mut run_start = 0;
+        let mut run = Vec::new();
+        for page_num in dirty {
+            let buf = match self.encode_page(page_num)? {
+                Some(buf) => buf,
+                None => continue,
+            };
+            if !run.is_empty() && page_num != run_start + run.len() / PAGE_SIZE {
+                self.write_at(run_start, &run)?;
+                run.clear();
+            }
+            if run.is_empty() {
+                run_start = page_num;
+            }
+            run.extend_from_slice(&buf);
+        }
+        if !run.is_empty() {
+            self.write_at(run_start, &run)?;
+        }
+        self.dirty.clear();
+        Ok(())
+    }
+
+    fn write_at(&mut self, page_num: usize, buf: &[u8]) -> Result<(), io::Error> {
+        let offset = self.page_offset(page_num);
+        self.fd.seek(io::SeekFrom::Start(offset))?;
+        self.fd.write_all(buf)?;
+        // Pages past the old end can now be evicted and read back.
+        self.file_size = cmp::max(self.file_size, offset + buf.len() as u64);
+        if self.sync_writes {
+            self.fd.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Page numbers are stored as `u32`s, so no file can have more pages than
+/// this. Memory and disk space run out long before.
+const TABLE_MAX_PAGES: usize = u32::MAX as usize;
+
+/// Pages kept in memory unless `--cache-size` says otherwise: 4 MiB worth.
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// Deepest a tree can legitimately get. A descent that goes further is
+/// following a pointer cycle in a corrupt file.
+const MAX_TREE_DEPTH: u32 = 64;
+
+fn check_depth(depth: u32, page_num: u32) -> Result<(), PagerError> {
+    if depth > MAX_TREE_DEPTH {
+        return Err(PagerError::Corrupt {
+            page_num: page_num as usize,
+        });
+    }
+    Ok(())
+}
+
+/// CRC-32 (IEEE) of `bytes`, as stored at the end of every page.
+fn page_checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Whether `page`, as read from disk, is whole and ends in the checksum of
+/// the rest of it.
+fn checksum_matches(page: &[u8]) -> bool {
+    if page.len() != PAGE_SIZE {
+        return false;
+    }
+    let mut stored = [0u8; btree::PAGE_CHECKSUM_SIZE];
+    stored.copy_from_slice(&page[NODE_SPACE..]);
+    u32::from_le_bytes(stored) == page_checksum(&page[..NODE_SPACE])
+}
+
+pub struct Table {
+    pub pager: Box<Pager>,
+    pub root_page_num: u32,
+}
+
+impl<'a> Table {
+    fn new(pager: Box<Pager>, root_page_num: u32) -> Self {
+        Table {
+            root_page_num,
+            pager,
+        }
+    }
+
+    /// Opens the database in `filename`, creating it if it doesn't exist.
+    pub fn open(filename: &str) -> Result<Table, Error> {
+        Ok(db_open(filename)?)
+    }
+
+    /// Inserts `row`, failing with `ExecuteError::DuplicateKey` if its id is
+    /// already taken.
+    pub fn insert(&mut self, row: &Row) -> Result<(), Error> {
+        Ok(self.insert_with(row, |_, _| Resolution::Error)?)
+    }
+
+    /// Writes every change back and syncs the file. Nothing is written until
+    /// this is called or pages are evicted.
+    pub fn close(&mut self) -> Result<(), Error> {
+        Ok(db_close(self)?)
+    }
+
+    /// Descends from the root to the leaf that holds, or would hold, `key`,
+    /// and positions a cursor at its cell there.
+    fn find(&mut self, key: Key) -> Result<Cursor<'_>, PagerError> {
+        let mut page_num = self.root_page_num;
+        let mut depth = 1;
+        let index = loop {
+            match self.pager.get_page(page_num as usize)? {
+                &Node::Leaf {
+                    num_cells,
+                    ref cells,
+                    ..
+                } => {
+                    break match cells[..num_cells as usize]
+                        .binary_search_by_key(&key, |cell| cell.key())
+                    {
+                        Ok(idx) => idx,
+                        Err(idx) => idx,
+                    }
+                }
+                node => page_num = node.child_for(key),
+            }
+            depth += 1;
+            check_depth(depth, page_num)?;
+        };
+        Ok(Cursor {
+            table: self,
+            page_num,
+            cell_num: index as u32,
+            end_of_table: false,
+        })
+    }
+
+    /// The metadata page as it is stored in the file, or `None` if it hasn't
+    /// been written yet, as in a new database before its first flush.
+    pub fn stored_meta(&mut self) -> io::Result<Option<Meta>> {
+        self.pager.read_meta()
+    }
+
+    /// Whether the table holds no rows. Doesn't create the root page if the
+    /// database is brand new, so asking never leaves anything to write back.
+    pub fn is_empty(&mut self) -> Result<bool, PagerError> {
+        let root_page_num = self.root_page_num as usize;
+        if !self.pager.page_exists(root_page_num) {
+            return Ok(true);
+        }
+        Ok(match self.pager.get_page(root_page_num)? {
+            &Node::Leaf { num_cells, .. } => num_cells == 0,
+            _ => false,
+        })
+    }
+
+    /// Positions a cursor at the first cell whose key is >= `key`, or at the
+    /// end of the table if every key is smaller. Unlike `find`, the cursor
+    /// is always either on a real row or flagged `end_of_table`.
+    fn find_ge(&mut self, key: Key) -> Result<Cursor<'_>, PagerError> {
+        let mut cursor = self.find(key)?;
+        // `key` may be past every key left in its leaf, but not the next one's.
+        cursor.skip_exhausted_leaves()?;
+        Ok(cursor)
+    }
+
+    /// Positions a cursor at the first cell whose key is > `key`.
+    fn find_gt(&mut self, key: Key) -> Result<Cursor<'_>, PagerError> {
+        match key.checked_add(1) {
+            Some(next) => self.find_ge(next),
+            None => self.end(),
+        }
+    }
+
+    /// The row with id `key`, if there is one.
+    fn get(&mut self, key: Key) -> Result<Option<Row>, PagerError> {
+        let mut cursor = self.find(key)?;
+        Ok(match cursor.get_value()? {
+            Some(row) if row.id == key => Some(*row),
+            _ => None,
+        })
+    }
+
+    /// The row with the smallest id greater than `key`.
+    pub fn successor(&mut self, key: Key) -> Result<Option<Row>, PagerError> {
+        Ok(self.find_gt(key)?.get_value()?.cloned())
+    }
+
+    /// The row with the largest id less than `key`.
+    pub fn predecessor(&mut self, key: Key) -> Result<Option<Row>, PagerError> {
+        let mut cursor = self.find_ge(key)?;
+        if cursor.retreat()? {
+            Ok(cursor.get_value()?.cloned())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Positions a cursor at the first row, in the leftmost leaf that has any.
+    fn start(&mut self) -> Result<Cursor<'_>, PagerError> {
+        let mut cursor = self.find(Key::MIN)?;
+        cursor.cell_num = 0;
+        cursor.skip_exhausted_leaves()?;
+        Ok(cursor)
+    }
+
+    /// Inserts `row`, asking `resolver` what to do if a row with the same id
+    /// already exists. The resolver receives the existing row and the new one.
+    pub fn insert_with<F>(&mut self, row: &Row, resolver: F) -> Result<(), ExecuteError>
+    where
+        F: FnMut(&Row, &Row) -> Resolution,
+    {
+        row.validate().map_err(ExecuteError::InvalidRow)?;
+        self.find(row.id)?.insert_or_resolve(row, resolver)
+    }
+
+    /// Inserts `rows` in order, failing on the first invalid or duplicate
+    /// one; the rows before it stay inserted. While consecutive rows keep
+    /// landing in the same leaf, that leaf is reused instead of descending
+    /// from the root again, so sorted batches rarely need `find`.
+    pub fn insert_many(&mut self, rows: &[Row]) -> Result<(), Error> {
+        let mut leaf = None;
+        for row in rows {
+            row.validate().map_err(ExecuteError::InvalidRow)?;
+            let warm = match leaf {
+                Some(page_num) => self.position_in_leaf(page_num, row.id)?
+                    .map(|cell_num| (page_num, cell_num)),
+                None => None,
+            };
+            let cursor = match warm {
+                Some((page_num, cell_num)) => Cursor {
+                    table: self,
+                    page_num,
+                    cell_num,
+                    end_of_table: false,
+                },
+                None => self.find(row.id)?,
+            };
+            leaf = Some(cursor.page_num);
+            cursor.insert_or_resolve(row, |_, _| Resolution::Error)?;
+        }
+        Ok(())
+    }
+
+    /// Where `key` goes in leaf `page_num`, provided that is the leaf `find`
+    /// would descend to: the page is still a leaf and `key` lies within its
+    /// keys, or past them if it is the last leaf. `None` otherwise.
+    fn position_in_leaf(&mut self, page_num: u32, key: Key) -> Result<Option<u32>, PagerError> {
+        Ok(match self.pager.get_page(page_num as usize)? {
+            &Node::Leaf {
+                num_cells,
+                ref cells,
+                next_leaf,
+                ..
+            } if num_cells > 0 => {
+                let cells = &cells[..num_cells as usize];
+                let last = cells[cells.len() - 1].key();
+                if cells[0].key() <= key && (next_leaf == 0 || key <= last) {
+                    Some(match cells.binary_search_by_key(&key, |cell| cell.key()) {
+                        Ok(idx) => idx as u32,
+                        Err(idx) => idx as u32,
+                    })
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        })
+    }
+
+    /// Inserts `row` at `cell_num` of full leaf `page_num` by splitting the
+    /// leaf, then hangs the new right half off the parent.
+    fn split_leaf_and_insert(
+        &mut self,
+        page_num: u32,
+        cell_num: u32,
+        row: &Row,
+    ) -> Result<(), ExecuteError> {
+        self.check_room_to_split()?;
+        let (split_key, right) = self.pager
+            .get_page_mut(page_num as usize)?
+            .split_and_insert(cell_num, row)
+            .expect("find positioned the cursor past the end of the leaf");
+        let right_page_num = self.pager.add_page(right)?;
+        if let &mut Node::Leaf {
+            ref mut next_leaf, ..
+        } = self.pager.get_page_mut(page_num as usize)?
+        {
+            *next_leaf = right_page_num;
+        }
+        Ok(self.insert_into_parent(page_num, split_key, right_page_num)?)
+    }
+
+    /// Overwrites the row at `cell_num` of leaf `page_num` with `row`, which
+    /// is too long to fit in its place, by taking the old row out and
+    /// splitting the leaf to put `row` back in.
+    fn split_leaf_and_replace(
+        &mut self,
+        page_num: u32,
+        cell_num: u32,
+        row: &Row,
+    ) -> Result<(), ExecuteError> {
+        // Before the old row goes, so that it stays if there's no room.
+        self.check_room_to_split()?;
+        self.pager
+            .get_page_mut(page_num as usize)?
+            .remove(cell_num)
+            .expect("replace found the cell");
+        self.split_leaf_and_insert(page_num, cell_num, row)
+    }
+
+    /// Fails with `TableFull` unless there are pages enough for a leaf split
+    /// to run its course: every level may split on the way up, plus one page
+    /// for a new root. Checking first means a split never stops halfway.
+    fn check_room_to_split(&mut self) -> Result<(), ExecuteError> {
+        let pages_needed = self.height()? as usize + 1;
+        if self.pager.pages_available() < pages_needed {
+            return Err(ExecuteError::TableFull);
+        }
+        Ok(())
+    }
+
+    /// Adds `right_page_num`, split off from `left_page_num`, to their
+    /// parent, splitting the parent in turn if it is full.
+    fn insert_into_parent(
+        &mut self,
+        left_page_num: u32,
+        key: Key,
+        right_page_num: u32,
+    ) -> Result<(), PagerError> {
+        if left_page_num == self.root_page_num {
+            return self.create_new_root(right_page_num);
+        }
+        let parent_page_num = self.pager
+            .get_page(left_page_num as usize)?
+            .header()
+            .parent_ptr;
+        let parent = self.pager.get_page_mut(parent_page_num as usize)?;
+        match parent.insert_child(left_page_num, key, right_page_num) {
+            Ok(()) => Ok(()),
+            Err(btree::NodeError::Full) => {
+                let (split_key, new_node) = parent
+                    .split_and_insert_child(left_page_num, key, right_page_num)
+                    .expect("insert_child found the child");
+                let new_page_num = self.pager.add_page(new_node)?;
+                self.adopt_children(new_page_num)?;
+                self.insert_into_parent(parent_page_num, split_key, new_page_num)
+            }
+            // The parent doesn't list the page that points at it.
+            Err(_) => Err(PagerError::Corrupt {
+                page_num: left_page_num as usize,
+            }),
+        }
+    }
+
+    /// Moves the root's contents to a new page, the left child, and turns
+    /// the root into an internal node over it and `right_child_page_num`.
+    /// The root keeps its page number, since that is what the metadata page
+    /// records as the table's entry point.
+    fn create_new_root(&mut self, right_child_page_num: u32) -> Result<(), PagerError> {
+        let root_page_num = self.root_page_num;
+        let mut left = mem::replace(
+            self.pager.get_page_mut(root_page_num as usize)?,
+            Node::create_leaf(),
+        );
+        left.header_mut().is_root = false;
+        left.header_mut().parent_ptr = root_page_num;
+        let left_page_num = self.pager.add_page(Box::new(left))?;
+        self.adopt_children(left_page_num)?;
+        self.pager
+            .get_page_mut(right_child_page_num as usize)?
+            .header_mut()
+            .parent_ptr = root_page_num;
+
+        let split_key = self.max_key(left_page_num)?;
+        let mut root = Node::create_internal(left_page_num, split_key, right_child_page_num);
+        root.header_mut().is_root = true;
+        *self.pager.get_page_mut(root_page_num as usize)? = root;
+        Ok(())
+    }
+
+    /// Points every child of internal node `page_num` back at it, after the
+    /// children moved there in a split.
+    fn adopt_children(&mut self, page_num: u32) -> Result<(), PagerError> {
+        let children = self.pager.get_page(page_num as usize)?.children();
+        for child in children {
+            self.pager
+                .get_page_mut(child as usize)?
+                .header_mut()
+                .parent_ptr = page_num;
+        }
+        Ok(())
+    }
+
+    /// The largest key under `page_num`.
+    fn max_key(&mut self, page_num: u32) -> Result<Key, PagerError> {
+        let leaf_page_num = self.rightmost_leaf(page_num)?;
+        let node = self.pager.get_page(leaf_page_num as usize)?;
+        Ok(node.keys().last().cloned().unwrap_or(0))
+    }
+
+    /// The last leaf under `page_num`, found along its rightmost path.
+    fn rightmost_leaf(&mut self, page_num: u32) -> Result<u32, PagerError> {
+        let mut page_num = page_num;
+        let mut depth = 1;
+        loop {
+            match self.pager.get_page(page_num as usize)? {
+                &Node::Leaf { .. } => return Ok(page_num),
+                &Node::Internal { right_child, .. } => page_num = right_child,
+            }
+            depth += 1;
+            check_depth(depth, page_num)?;
+        }
+    }
+
+    /// The leaf before leaf `page_num` in key order. Leaves only link
+    /// forwards, so this climbs the parent pointers until it can step left.
+    fn previous_leaf(&mut self, page_num: u32) -> Result<Option<u32>, PagerError> {
+        let mut child = page_num;
+        let mut depth = 1;
+        while child != self.root_page_num {
+            let parent = self.pager.get_page(child as usize)?.header().parent_ptr;
+            let siblings = self.pager.get_page(parent as usize)?.children();
+            match siblings.iter().position(|&sibling| sibling == child) {
+                Some(0) => child = parent,
+                Some(idx) => return self.rightmost_leaf(siblings[idx - 1]).map(Some),
+                None => {
+                    return Err(PagerError::Corrupt {
+                        page_num: child as usize,
+                    })
+                }
+            }
+            depth += 1;
+            check_depth(depth, child)?;
+        }
+        Ok(None)
+    }
+
+    /// Unhooks empty leaf `page_num` from its neighbours and its parent and
+    /// frees it, along with any ancestors that are left with no children. If
+    /// that would empty the root, the root becomes an empty leaf instead.
+    /// Returns the leaf before it, if any.
+    fn free_empty_leaf(&mut self, page_num: u32) -> Result<Option<u32>, PagerError> {
+        let next = match self.pager.get_page(page_num as usize)? {
+            &Node::Leaf { next_leaf, .. } => next_leaf,
+            _ => unreachable!("free_empty_leaf is only called on leaves"),
+        };
+        let previous = self.previous_leaf(page_num)?;
+        if let Some(previous) = previous {
+            if let &mut Node::Leaf {
+                ref mut next_leaf, ..
+            } = self.pager.get_page_mut(previous as usize)?
+            {
+                *next_leaf = next;
+            }
+        }
+        let mut child = page_num;
+        let mut depth = 1;
+        loop {
+            let parent = self.pager.get_page(child as usize)?.header().parent_ptr;
+            let remaining = self.pager
+                .get_page_mut(parent as usize)?
+                .remove_child(child)
+                .map_err(|_| PagerError::Corrupt {
+                    page_num: child as usize,
+                })?;
+            self.pager.free_page(child);
+            if remaining > 0 {
+                break;
+            }
+            if parent == self.root_page_num {
+                let mut root = Node::create_leaf();
+                root.header_mut().is_root = true;
+                *self.pager.get_page_mut(parent as usize)? = root;
+                break;
+            }
+            child = parent;
+            depth += 1;
+            check_depth(depth, child)?;
+        }
+        Ok(previous)
+    }
+
+    /// Number of levels in the tree, counting the leaves.
+    fn height(&mut self) -> Result<u32, PagerError> {
+        let mut page_num = self.root_page_num;
+        let mut depth = 1;
+        loop {
+            match self.pager.get_page(page_num as usize)? {
+                &Node::Leaf { .. } => return Ok(depth),
+                node => page_num = node.children()[0],
+            }
+            depth += 1;
+            check_depth(depth, page_num)?;
+        }
+    }
+
+    /// Returns every row in id order.
+    pub fn select(&mut self) -> Result<Vec<PublicRow>, Error> {
+        let mut rows = Vec::new();
+        self.select_stream(|row| {
+            rows.push(PublicRow::from(*row));
+            Scan::Continue
+        })?;
+        Ok(rows)
+    }
+
+    /// Hands each row to `sink` in id order as the scan reaches it, without
+    /// collecting them, until the rows run out or `sink` returns `Scan::Stop`.
+    pub fn select_stream<F: FnMut(&Row) -> Scan>(&mut self, mut sink: F) -> Result<(), PagerError> {
+        let mut cursor = self.start()?;
+        while !cursor.end_of_table {
+            let keep_going = match cursor.get_value()? {
+                Some(row) => sink(row) == Scan::Continue,
+                None => false,
+            };
+            if !keep_going {
+                break;
+            }
+            cursor.advance()?;
+        }
+        Ok(())
+    }
+
+    /// Positions a cursor at the first row within `range`'s lower bound.
+    fn seek(&mut self, range: &RangeBound) -> Result<Cursor<'_>, PagerError> {
+        match range.lower {
+            None => self.start(),
+            Some(KeyBound {
+                key,
+                inclusive: true,
+            }) => self.find_ge(key),
+            Some(KeyBound {
+                key,
+                inclusive: false,
+            }) => self.find_gt(key),
+        }
+    }
+
+    /// Hands each row in `range` to `sink` in id order, seeking straight to
+    /// the start of the range and stopping at its end.
+    fn select_range<F: FnMut(&Row)>(
+        &mut self,
+        range: &RangeBound,
+        mut sink: F,
+    ) -> Result<(), PagerError> {
+        let mut cursor = self.seek(range)?;
+        while !cursor.end_of_table {
+            match cursor.get_value()? {
+                Some(row) if range.below_upper(row.id) => sink(row),
+                _ => break,
+            }
+            cursor.advance()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the row with id `key`, returning it. A leaf that this empties
+    /// is taken out of the tree and its page freed, but leaves that are
+    /// merely sparse are left as they are.
+    fn delete(&mut self, key: Key) -> Result<Row, ExecuteError> {
+        let mut cursor = self.find(key)?;
+        match cursor.get_value()? {
+            Some(row) if row.id == key => {}
+            _ => return Err(ExecuteError::NoSuchKey),
+        }
+        Ok(cursor.remove()?)
+    }
+
+    /// Deletes every row in `range`, returning how many were removed.
+    fn delete_range(&mut self, range: &RangeBound) -> Result<usize, PagerError> {
+        let mut cursor = self.seek(range)?;
+        let mut removed = 0;
+        while !cursor.end_of_table {
+            match cursor.get_value()? {
+                Some(row) if range.below_upper(row.id) => {}
+                _ => break,
+            }
+            cursor.remove()?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Keyset pagination: up to `page_size` rows with ids greater than
+    /// `after` (or from the start if `None`), plus the id to pass as `after`
+    /// for the next page, or `None` once there are no more rows. A
+    /// `page_size` of 0 is rejected, since its pages could never advance.
+    pub fn select_page(
+        &mut self,
+        after: Option<Key>,
+        page_size: u32,
+    ) -> Result<(Vec<Row>, Option<Key>), Error> {
+        if page_size == 0 {
+            let msg = "page size must be at least 1";
+            return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput, msg)));
+        }
+        let mut cursor = match after {
+            Some(key) => self.find_gt(key)?,
+            None => self.start()?,
+        };
+        let mut rows = Vec::new();
+        while !cursor.end_of_table && rows.len() < page_size as usize {
+            match cursor.get_value()? {
+                Some(row) => rows.push(*row),
+                None => break,
+            }
+            cursor.advance()?;
+        }
+        let next = if cursor.end_of_table {
+            None
+        } else {
+            rows.last().map(|row| row.id)
+        };
+        Ok((rows, next))
+    }
+
+    /// Visits every node reachable from the root, parents before children,
+    /// along with its depth (the root is at depth 1).
+    fn walk<F: FnMut(&Node, u32)>(&mut self, visit: &mut F) -> Result<(), PagerError> {
+        let root_page_num = self.root_page_num;
+        self.walk_from(root_page_num, 1, visit)
+    }
+
+    fn walk_from<F: FnMut(&Node, u32)>(
+        &mut self,
+        page_num: u32,
+        depth: u32,
+        visit: &mut F,
+    ) -> Result<(), PagerError> {
+        check_depth(depth, page_num)?;
+        let children = {
+            let node = self.pager.get_page(page_num as usize)?;
+            visit(node, depth);
+            node.children()
+        };
+        for child in children {
+            self.walk_from(child, depth + 1, visit)?;
+        }
+        Ok(())
+    }
+
+    /// Page numbers of every leaf, left to right, found by descending
+    /// through the internal nodes rather than following `next_leaf`.
+    fn leaf_pages(&mut self) -> Result<Vec<u32>, PagerError> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![(self.root_page_num, 1)];
+        while let Some((page_num, depth)) = stack.pop() {
+            check_depth(depth, page_num)?;
+            let node = self.pager.get_page(page_num as usize)?;
+            match *node {
+                Node::Leaf { .. } => leaves.push(page_num),
+                _ => stack.extend(node.children().into_iter().rev().map(|c| (c, depth + 1))),
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Rewrites every leaf's `next_leaf` to point at the leaf after it in key
+    /// order, repairing a broken sibling chain. Returns how many pointers
+    /// were wrong.
+    pub fn relink(&mut self) -> Result<usize, PagerError> {
+        let leaves = self.leaf_pages()?;
+        let mut relinked = 0;
+        for (i, &page_num) in leaves.iter().enumerate() {
+            let right = leaves.get(i + 1).cloned().unwrap_or(0);
+            // Looked at first so that leaves already right aren't marked dirty.
+            if let &Node::Leaf { next_leaf, .. } = self.pager.get_page(page_num as usize)? {
+                if next_leaf == right {
+                    continue;
+                }
+            }
+            if let &mut Node::Leaf {
+                ref mut next_leaf, ..
+            } = self.pager.get_page_mut(page_num as usize)?
+            {
+                *next_leaf = right;
+            }
+            relinked += 1;
+        }
+        Ok(relinked)
+    }
+
+    pub fn tree_stats(&mut self) -> Result<TreeStats, PagerError> {
+        let mut stats = TreeStats {
+            leaves: 0,
+            internals: 0,
+            height: 0,
+            leaf_cells: 0,
+            leaf_bytes: 0,
+            min_leaf_bytes: usize::MAX,
+        };
+        self.walk(&mut |node, depth| {
+            stats.height = cmp::max(stats.height, depth);
+            match node {
+                &Node::Leaf { num_cells, .. } => {
+                    stats.leaves += 1;
+                    stats.leaf_cells += num_cells as usize;
+                    stats.leaf_bytes += node.encoded_len();
+                    stats.min_leaf_bytes = cmp::min(stats.min_leaf_bytes, node.encoded_len());
+                }
+                _ => stats.internals += 1,
+            }
+        })?;
+        Ok(stats)
+    }
+
+    /// Returns the number of (leaf, internal) pages in the tree.
+    pub fn page_type_counts(&mut self) -> Result<(usize, usize), PagerError> {
+        let stats = self.tree_stats()?;
+        Ok((stats.leaves, stats.internals))
+    }
+
+    fn tree_view(&mut self, page_num: u32, depth: u32) -> Result<TreeView, PagerError> {
+        check_depth(depth, page_num)?;
+        let (node_type, keys, children) = {
+            let node = self.pager.get_page(page_num as usize)?;
+            let node_type = match node {
+                &Node::Leaf { .. } => "leaf",
+                &Node::Internal { .. } => "internal",
+            };
+            (node_type, node.keys(), node.children())
+        };
+        let mut child_views = Vec::with_capacity(children.len());
+        for child in children {
+            child_views.push(self.tree_view(child, depth + 1)?);
+        }
+        Ok(TreeView {
+            node_type,
+            page_num,
+            keys,
+            children: child_views,
+        })
+    }
+
+    /// Renders the tree under `page_num` for `.btree`, one line per key or
+    /// child, with each level indented under its parent.
+    pub fn write_tree(
+        &mut self,
+        page_num: u32,
+        depth: u32,
+        out: &mut String,
+    ) -> Result<(), PagerError> {
+        check_depth(depth, page_num)?;
+        let indent = "  ".repeat(depth as usize - 1);
+        let item = if depth == 1 { "" } else { "- " };
+        let (keys, children) = {
+            let node = self.pager.get_page(page_num as usize)?;
+            (node.keys(), node.children())
+        };
+        if children.is_empty() {
+            out.push_str(&format!("{}{}leaf (size {})\n", indent, item, keys.len()));
+            for (i, key) in keys.iter().enumerate() {
+                out.push_str(&format!("{}  - {} : {}\n", indent, i, key));
+            }
+            return Ok(());
+        }
+        out.push_str(&format!("{}{}internal (size {})\n", indent, item, keys.len()));
+        for (i, &child) in children.iter().enumerate() {
+            self.write_tree(child, depth + 1, out)?;
+            if let Some(key) = keys.get(i) {
+                out.push_str(&format!("{}  - key {}\n", indent, key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the tree as JSON for external visualizers: every node has its
+    /// `type`, `page`, `keys` and `children`.
+    pub fn tree_to_json(&mut self) -> Result<String, PagerError> {
+        let root_page_num = self.root_page_num;
+        let view = self.tree_view(root_page_num, 1)?;
+        Ok(serde_json::to_string(&view).expect("serializing a tree view cannot fail"))
+    }
+
+    /// Estimates how many rows have ids in `[lo, hi]` from the tree structure
+    /// alone, so callers can choose between a seek and a full scan.
+    pub fn estimate_count(&mut self, lo: Key, hi: Key) -> Result<u64, PagerError> {
+        if lo > hi {
+            return Ok(0);
+        }
+        let root_page_num = self.root_page_num;
+        self.estimate_from(root_page_num, lo, hi, 1)
+    }
+
+    /// Counts the edge leaves for `lo` and `hi` exactly, and assumes every
+    /// subtree strictly between them is shaped like the first of them.
+    fn estimate_from(
+        &mut self,
+        page_num: u32,
+        lo: Key,
+        hi: Key,
+        depth: u32,
+    ) -> Result<u64, PagerError> {
+        check_depth(depth, page_num)?;
+        let (children, first, last) = match self.pager.get_page(page_num as usize)? {
+            &Node::Leaf {
+                num_cells,
+                ref cells,
+                ..
+            } => {
+                let keys = &cells[..num_cells as usize];
+                let start = match keys.binary_search_by_key(&lo, |cell| cell.key()) {
+                    Ok(idx) | Err(idx) => idx,
+                };
+                let end = match keys.binary_search_by_key(&hi, |cell| cell.key()) {
+                    Ok(idx) => idx + 1,
+                    Err(idx) => idx,
+                };
+                return Ok((end - start) as u64);
+            }
+            node => (node.children(), node.child_index_for(lo), node.child_index_for(hi)),
+        };
+        if first == last {
+            return self.estimate_from(children[first], lo, hi, depth + 1);
+        }
+        let mut estimate = self.estimate_from(children[first], lo, hi, depth + 1)?
+            + self.estimate_from(children[last], lo, hi, depth + 1)?;
+        if last - first > 1 {
+            let per_child = self.estimate_subtree_rows(children[first + 1], depth + 1)?;
+            estimate += (last - first - 1) as u64 * per_child;
+        }
+        Ok(estimate)
+    }
+
+    /// Estimates the rows under `page_num` from the fan-out along its
+    /// leftmost path.
+    fn estimate_subtree_rows(&mut self, page_num: u32, depth: u32) -> Result<u64, PagerError> {
+        check_depth(depth, page_num)?;
+        let (fan_out, first_child) = match self.pager.get_page(page_num as usize)? {
+            &Node::Leaf { num_cells, .. } => return Ok(num_cells as u64),
+            node => {
+                let children = node.children();
+                (children.len() as u64, children[0])
+            }
+        };
+        Ok(fan_out * self.estimate_subtree_rows(first_child, depth + 1)?)
+    }
+
+    /// Positions a cursor just past the last cell of the rightmost leaf.
+    fn end(&mut self) -> Result<Cursor<'_>, PagerError> {
+        let page_num = self.find(Key::MAX)?.page_num;
+        let cell_num = match self.pager.get_page(page_num as usize)? {
+            &Node::Leaf { num_cells, .. } => num_cells,
+            _ => unreachable!("find always stops at a leaf"),
+        };
+        Ok(Cursor {
+            table: self,
+            page_num,
+            cell_num,
+            end_of_table: true,
+        })
+    }
+}
+
+/// Returned by `Table::select_stream` sinks to continue or end the scan.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scan {
+    Continue,
+    Stop,
+}
+
+pub struct TreeStats {
+    pub leaves: usize,
+    pub internals: usize,
+    pub height: u32,
+    pub leaf_cells: usize,
+    /// Bytes the leaves take encoded, out of a page each.
+    pub leaf_bytes: usize,
+    /// Encoded bytes of the emptiest leaf.
+    pub min_leaf_bytes: usize,
+}
+
+impl TreeStats {
+    /// Mean fraction of leaf pages in use, as a percentage.
+    pub fn average_leaf_fill(&self) -> f64 {
+        if self.leaves == 0 {
+            return 0.0;
+        }
+        100.0 * self.leaf_bytes as f64 / (self.leaves * PAGE_SIZE) as f64
+    }
+
+    /// Fraction of the emptiest leaf's page in use, as a percentage.
+    pub fn min_leaf_fill(&self) -> f64 {
+        if self.leaves == 0 {
+            return 0.0;
+        }
+        100.0 * self.min_leaf_bytes as f64 / PAGE_SIZE as f64
+    }
+}
+
+/// A lightweight copy of one node's shape, used to render the tree as JSON.
+#[derive(Serialize)]
+struct TreeView {
+    #[serde(rename = "type")]
+    node_type: &'static str,
+    #[serde(rename = "page")]
+    page_num: u32,
+    keys: Vec<Key>,
+    children: Vec<TreeView>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Unrecognized,
+    InvalidSyntax,
+    StringTooLong,
+    NegativeID,
+    InvalidUtf8,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Column {
+    Id,
+    Username,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyBound {
+    key: Key,
+    inclusive: bool,
+}
+
+/// A key range with optional, independently inclusive or exclusive ends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeBound {
+    lower: Option<KeyBound>,
+    upper: Option<KeyBound>,
+}
+
+impl RangeBound {
+    /// The range `[lo, hi]`.
+    fn between(lo: Key, hi: Key) -> Self {
+        RangeBound {
+            lower: Some(KeyBound {
+                key: lo,
+                inclusive: true,
+            }),
+            upper: Some(KeyBound {
+                key: hi,
+                inclusive: true,
+            }),
+        }
+    }
+
+    fn below_upper(&self, key: Key) -> bool {
+        match self.upper {
+            None => true,
+            Some(KeyBound {
+                key: hi,
+                inclusive: true,
+            }) => key <= hi,
+            Some(KeyBound {
+                key: hi,
+                inclusive: false,
+            }) => key < hi,
+        }
+    }
+}
+
+pub enum Statement {
+    Insert(Row),
+    Update(Row),
+    Select { order_by: Column, descending: bool },
+    SelectOne(Key),
+    SelectRange(Key, Key),
+    SelectHead(u32),
+    SelectTail(u32),
+    SelectWhere(RangeBound),
+    Delete(Key),
+    DeleteWhere(RangeBound),
+}
+
+/// Parses a row id, which must be non-negative and fit in a `Key`.
+fn parse_id(id_str: &str) -> Result<Key, ParseError> {
+    let id = id_str
+        .parse::<i64>()
+        .map_err(|_| ParseError::InvalidSyntax)?;
+    if id < 0 {
+        return Err(ParseError::NegativeID);
+    }
+    if id as u64 > Key::MAX as u64 {
+        return Err(ParseError::InvalidSyntax);
+    }
+    Ok(id as Key)
+}
+
+/// Inserts a row read by an import into `table`, or counts it as invalid if
+/// its record didn't parse, and returns whether it was loaded. Only a pager
+/// error stops the import.
+fn import_row(
+    table: &mut Table,
+    summary: &mut csv::ImportSummary,
+    row: Option<Row>,
+) -> io::Result<bool> {
+    let row = match row {
+        Some(row) => row,
+        None => {
+            summary.invalid += 1;
+            return Ok(false);
+        }
+    };
+    match table.insert_with(&row, |_, _| Resolution::Error) {
+        Ok(()) => {
+            summary.loaded += 1;
+            Ok(true)
+        }
+        Err(ExecuteError::Pager(e)) => Err(e.into()),
+        Err(ExecuteError::DuplicateKey) => {
+            summary.duplicates += 1;
+            Ok(false)
+        }
+        Err(_) => {
+            summary.invalid += 1;
+            Ok(false)
+        }
+    }
+}
+
+/// Checks the fields of a row the way `insert` does, returning the id.
+fn parse_fields(id_str: &str, username: &[u8], email: &[u8]) -> Result<Key, ParseError> {
+    let id = parse_id(id_str)?;
+    if username.len() > MAX_UNAME_LENGTH || email.len() > MAX_EMAIL_LENGTH {
+        return Err(ParseError::StringTooLong);
+    }
+    Ok(id)
+}
+
+/// Splits `input` on whitespace, except inside double quotes, so a quoted
+/// token can hold spaces. The quotes themselves aren't part of the token.
+fn tokenize(input: &str) -> Result<Vec<&str>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let (token, after) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').ok_or(ParseError::InvalidSyntax)?;
+            let after = &quoted[end + 1..];
+            // `"a"b` is neither one token nor two.
+            if !after.is_empty() && !after.starts_with(char::is_whitespace) {
+                return Err(ParseError::InvalidSyntax);
+            }
+            (&quoted[..end], after)
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        tokens.push(token);
+        rest = after.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses an `insert` (or `update`) statement into `row`, overwriting its
+/// previous contents.
+///
+/// Lets callers running many inserts reuse one `Row` instead of building a
+/// fresh one per statement.
+pub fn prepare_insert_into(input: &str, row: &mut Row) -> Result<(), ParseError> {
+    let tokens = tokenize(input)?;
+    let mut tokens = tokens.into_iter().skip(1); // skip "insert"
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some(id_str), Some(username), Some(email)) => {
+            let (uname_bytes, email_bytes) = (username.as_bytes(), email.as_bytes());
+            let id = parse_fields(id_str, uname_bytes, email_bytes)?;
+            row.fill(id, uname_bytes, email_bytes);
+            Ok(())
+        }
+        _ => Err(ParseError::InvalidSyntax),
+    }
+}
+
+/// Parses `select where id OP N [and id OP N]` (or `delete where ...`),
+/// where OP is one of `>`, `>=`, `<` or `<=`, into the key range it covers.
+fn prepare_where(input: &str) -> Result<RangeBound, ParseError> {
+    let mut tokens = input.split_whitespace().skip(2); // skip "select where"
+    let mut range = RangeBound {
+        lower: None,
+        upper: None,
+    };
+    loop {
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some("id"), Some(op), Some(n)) => {
+                let key = n.parse::<Key>().map_err(|_| ParseError::InvalidSyntax)?;
+                let (bound, inclusive) = match op {
+                    ">" => (&mut range.lower, false),
+                    ">=" => (&mut range.lower, true),
+                    "<" => (&mut range.upper, false),
+                    "<=" => (&mut range.upper, true),
+                    _ => return Err(ParseError::InvalidSyntax),
+                };
+                if bound.is_some() {
+                    return Err(ParseError::InvalidSyntax);
+                }
+                *bound = Some(KeyBound { key, inclusive });
+            }
+            _ => return Err(ParseError::InvalidSyntax),
+        }
+        match tokens.next() {
+            None => return Ok(range),
+            Some("and") => {}
+            Some(_) => return Err(ParseError::InvalidSyntax),
+        }
+    }
+}
+
+pub fn prepare_statement(input: &str) -> Result<Statement, ParseError> {
+    if input.starts_with("insert") {
+        let mut row = Row::default();
+        prepare_insert_into(input, &mut row)?;
+        Ok(Statement::Insert(row))
+    } else if input.starts_with("update") {
+        let mut row = Row::default();
+        prepare_insert_into(input, &mut row)?;
+        Ok(Statement::Update(row))
+    } else if input.starts_with("delete") {
+        let mut tokens = input.split_whitespace().skip(1);
+        match (tokens.next(), tokens.next()) {
+            (Some("where"), _) => prepare_where(input).map(Statement::DeleteWhere),
+            (Some(id), None) => parse_id(id).map(Statement::Delete),
+            _ => Err(ParseError::InvalidSyntax),
+        }
+    } else if input.starts_with("select") {
+        if input.split_whitespace().nth(1) == Some("where") {
+            return prepare_where(input).map(Statement::SelectWhere);
+        }
+        let mut tokens = input.split_whitespace();
+        let _ = tokens.next(); // skip "select"
+        let (order_by, direction) = match (tokens.next(), tokens.next(), tokens.next()) {
+            (None, ..) => (Column::Id, None),
+            (Some(id), None, None) => return parse_id(id).map(Statement::SelectOne),
+            (Some(end @ "head"), Some(n), None) | (Some(end @ "tail"), Some(n), None) => {
+                let n = n.parse::<u32>().map_err(|_| ParseError::InvalidSyntax)?;
+                return Ok(if end == "head" {
+                    Statement::SelectHead(n)
+                } else {
+                    Statement::SelectTail(n)
+                });
+            }
+            (Some(lo), Some(hi), None) => {
+                let (lo, hi) = (parse_id(lo)?, parse_id(hi)?);
+                if lo > hi {
+                    return Err(ParseError::InvalidSyntax);
+                }
+                return Ok(Statement::SelectRange(lo, hi));
+            }
+            (Some("order"), Some("by"), Some(column)) => {
+                let column = match column {
+                    "id" => Column::Id,
+                    "username" => Column::Username,
+                    _ => return Err(ParseError::InvalidSyntax),
+                };
+                (column, tokens.next())
+            }
+            _ => return Err(ParseError::InvalidSyntax),
+        };
+        let descending = match (direction, tokens.next()) {
+            (None, None) | (Some("asc"), None) => false,
+            (Some("desc"), None) => true,
+            _ => return Err(ParseError::InvalidSyntax),
+        };
+        Ok(Statement::Select {
+            order_by,
+            descending,
+        })
+    } else {
+        Err(ParseError::Unrecognized)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExecuteError {
+    DuplicateKey,
+    NoSuchKey,
+    TableFull,
+    InvalidRow(ParseError),
+    Pager(PagerError),
+}
+
+impl From<PagerError> for ExecuteError {
+    fn from(e: PagerError) -> Self {
+        ExecuteError::Pager(e)
+    }
+}
+
+/// What `Table`'s public methods fail with.
+#[derive(Debug)]
+pub enum Error {
+    /// The file couldn't be read or written, or isn't a database.
+    Io(io::Error),
+    Execute(ExecuteError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Execute(ExecuteError::DuplicateKey) => write!(f, "duplicate key"),
+            Error::Execute(ExecuteError::NoSuchKey) => write!(f, "no such key"),
+            Error::Execute(ExecuteError::TableFull) => write!(f, "table full"),
+            Error::Execute(ExecuteError::InvalidRow(ref e)) => write!(f, "invalid row ({:?})", e),
+            Error::Execute(ExecuteError::Pager(ref e)) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ExecuteError> for Error {
+    fn from(e: ExecuteError) -> Self {
+        Error::Execute(e)
+    }
+}
+
+impl From<PagerError> for Error {
+    fn from(e: PagerError) -> Self {
+        Error::Execute(ExecuteError::Pager(e))
+    }
+}
+
+/// How `Table::insert_with` should handle an insert whose id is already taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    KeepExisting,
+    UseNew,
+    Error,
+}
+
+/// What a statement produced, for the caller to show. Rows come in the
+/// order they should be shown in.
+pub enum Output {
+    Done,
+    Rows(Vec<Row>),
+    /// The rows visited before `scan_budget` ran out.
+    PartialRows(Vec<Row>),
+    Deleted(usize),
+}
+
+fn execute_insert(row: &Row, table: &mut Table) -> Result<(), ExecuteError> {
+    table.insert_with(row, |_, _| Resolution::Error)
+}
+
+/// Returns every row, visiting at most `scan_budget` leaf cells if one is
+/// set, along with whether the budget ran out first.
+///
+/// Rows come out of the tree in id order; any other ordering is sorted in
+/// memory.
+fn execute_select(
+    table: &mut Table,
+    order_by: Column,
+    descending: bool,
+    scan_budget: Option<u32>,
+) -> Result<(Vec<Row>, bool), ExecuteError> {
+    let mut rows = Vec::new();
+    let mut partial = false;
+    table.select_stream(|row| {
+        if scan_budget.is_some_and(|budget| rows.len() as u32 >= budget) {
+            partial = true;
+            return Scan::Stop;
+        }
+        rows.push(*row);
+        Scan::Continue
+    })?;
+    match order_by {
+        Column::Id => {}
+        Column::Username => rows.sort_by(|a, b| a.username().cmp(b.username())),
+    }
+    if descending {
+        rows.reverse();
+    }
+    Ok((rows, partial))
+}
+
+/// Returns the row with id `key`, if there is one.
+fn execute_select_one(table: &mut Table, key: Key) -> Result<Vec<Row>, ExecuteError> {
+    Ok(table.get(key)?.into_iter().collect())
+}
+
+/// Returns the `n` rows with the lowest ids.
+fn execute_select_head(table: &mut Table, n: u32) -> Result<Vec<Row>, ExecuteError> {
+    let mut rows = Vec::new();
+    table.select_stream(|row| {
+        if rows.len() as u32 >= n {
+            return Scan::Stop;
+        }
+        rows.push(*row);
+        Scan::Continue
+    })?;
+    Ok(rows)
+}
+
+/// Returns the `n` rows with the highest ids, still in ascending order.
+fn execute_select_tail(table: &mut Table, n: u32) -> Result<Vec<Row>, ExecuteError> {
+    let mut rows = Vec::new();
+    {
+        let mut cursor = table.end()?;
+        while (rows.len() as u32) < n && cursor.retreat()? {
+            match cursor.get_value()? {
+                Some(row) => rows.push(*row),
+                None => break,
+            }
+        }
+    }
+    rows.reverse();
+    Ok(rows)
+}
+
+/// Returns the rows whose ids fall in `range`, seeking straight to its start.
+fn execute_select_where(table: &mut Table, range: RangeBound) -> Result<Vec<Row>, ExecuteError> {
+    let mut rows = Vec::new();
+    table.select_range(&range, |row| rows.push(*row))?;
+    Ok(rows)
+}
+
+/// Overwrites the username and email of the existing row with `row`'s id.
+fn execute_update(row: &Row, table: &mut Table) -> Result<(), ExecuteError> {
+    row.validate().map_err(ExecuteError::InvalidRow)?;
+    let mut cursor = table.find(row.id)?;
+    match cursor.get_value()? {
+        Some(existing) if existing.id == row.id => {}
+        _ => return Err(ExecuteError::NoSuchKey),
+    }
+    cursor.set_value(row)
+}
+
+fn execute_delete(table: &mut Table, key: Key) -> Result<(), ExecuteError> {
+    table.delete(key).map(|_| ())
+}
+
+/// Returns how many rows were deleted.
+fn execute_delete_where(table: &mut Table, range: RangeBound) -> Result<usize, ExecuteError> {
+    Ok(table.delete_range(&range)?)
+}
+
+pub fn execute_statement(
+    statement: Statement,
+    table: &mut Table,
+    scan_budget: Option<u32>,
+) -> Result<Output, ExecuteError> {
+    Ok(match statement {
+        Statement::Insert(row) => execute_insert(&row, table).map(|()| Output::Done)?,
+        Statement::Update(row) => execute_update(&row, table).map(|()| Output::Done)?,
+        Statement::Select {
+            order_by,
+            descending,
+        } => match execute_select(table, order_by, descending, scan_budget)? {
+            (rows, false) => Output::Rows(rows),
+            (rows, true) => Output::PartialRows(rows),
+        },
+        Statement::SelectOne(key) => Output::Rows(execute_select_one(table, key)?),
+        Statement::SelectRange(lo, hi) => {
+            Output::Rows(execute_select_where(table, RangeBound::between(lo, hi))?)
+        }
+        Statement::SelectHead(n) => Output::Rows(execute_select_head(table, n)?),
+        Statement::SelectTail(n) => Output::Rows(execute_select_tail(table, n)?),
+        Statement::SelectWhere(range) => Output::Rows(execute_select_where(table, range)?),
+        Statement::Delete(key) => execute_delete(table, key).map(|()| Output::Done)?,
+        Statement::DeleteWhere(range) => Output::Deleted(execute_delete_where(table, range)?),
+    })
+}
+
+pub fn db_open(filename: &str) -> Result<Table, io::Error> {
+    db_open_with_key(filename, None)
+}
+
+/// Opens `filename`, decrypting it with `key` if given. An encrypted file
+/// can't be opened without its key, and a key can't be used on a plain file.
+pub fn db_open_with_key(filename: &str, key: Option<&str>) -> Result<Table, io::Error> {
+    let mut pager = Pager::open(filename, DEFAULT_CACHE_SIZE)?;
+    match key {
+        Some(key) => pager.enable_encryption(key)?,
+        None => if pager.is_encrypted()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "database is encrypted; pass --key",
+            ));
+        },
+    }
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let torn = |page_num| invalid(format!("page {} is torn or corrupt", page_num));
+    let root_page_num = if pager.pages_on_disk() == 0 {
+        meta::DEFAULT_ROOT_PAGE_NUM
+    } else {
+        match pager.read_meta()? {
+            Some(meta) => {
+                if meta.version != meta::FORMAT_VERSION {
+                    return invalid(format!("unsupported format version {}", meta.version));
+                }
+                if meta.page_size as usize != PAGE_SIZE {
+                    return invalid(format!("unsupported page size {}", meta.page_size));
+                }
+                let key_size = mem::size_of::<Key>() as u32;
+                if meta.key_size != key_size {
+                    return invalid(format!(
+                        "database has {}-bit ids, but this build uses {}-bit ids",
+                        meta.key_size * 8,
+                        key_size * 8
+                    ));
+                }
+                if meta.root_page_num >= meta.num_pages {
+                    return invalid(format!("bad page count {}", meta.num_pages));
+                }
+                // Don't count preallocated pages as in use.
+                pager.num_pages = meta.num_pages as usize;
+                if pager.load_free_list(meta.free_list_head).is_err() {
+                    return invalid(format!("bad free list at page {}", meta.free_list_head));
+                }
+                meta.root_page_num
+            }
+            None => {
+                // Without a metadata page, only a version 1 file, whose first
+                // page is its root leaf, is a database at all.
+                if pager.migrate_headerless(meta::DEFAULT_ROOT_PAGE_NUM as usize).is_err() {
+                    return invalid("not a valid database file".to_string());
+                }
+                meta::DEFAULT_ROOT_PAGE_NUM
+            }
+        }
+    };
+    // Other pages are checked against their checksums as they are read, but
+    // a torn root would leave nothing to read at all.
+    let root = root_page_num as usize;
+    if pager.page_exists(root) && pager.load_page(root).is_err() {
+        return torn(root);
+    }
+    let table = Table::new(pager, root_page_num);
+    Ok(table)
+}
+
+/// Writes everything back to disk, leaving the table open.
+pub fn db_flush(table: &mut Table) -> Result<(), io::Error> {
+    table.pager.flush_all()?;
+    // An untouched new database stays an empty file.
+    if table.pager.page_exists(table.root_page_num as usize) {
+        let mut meta = Meta::new(table.root_page_num, table.pager.num_pages as u32);
+        meta.free_list_head = table.pager.free_list_head();
+        table.pager.write_meta(&meta)?;
+    }
+    Ok(())
+}
+
+/// Flushes the table and waits for the writes to reach the disk, so that
+/// everything written before a clean close survives a crash right after it.
+/// Freed pages at the end of the file are cut off first.
+pub fn db_close(table: &mut Table) -> Result<(), io::Error> {
+    table.pager.truncate_free_tail()?;
+    db_flush(table)?;
+    table.pager.fd.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::mem::size_of;
+
+    fn test_db_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("db_tutorial_{}.db", name));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn open_test_table(name: &str) -> Table {
+        let path = test_db_path(name);
+        let _ = fs::remove_file(&path);
+        db_open(&path).unwrap()
+    }
+
+    fn scan(table: &mut Table) -> Vec<String> {
+        let mut rows = Vec::new();
+        let mut cursor = table.start().unwrap();
+        while let Some(row) = cursor.get_value().unwrap().map(|row| row.to_string()) {
+            rows.push(row);
+            cursor.advance().unwrap();
+        }
+        rows
+    }
+
+    /// A row of the maximum length. A leaf holds only a dozen or so, so a
+    /// few dozen of them are enough to split leaves and grow the tree.
+    fn wide_row(id: Key) -> Row {
+        Row::new(id, &[b'u'; MAX_UNAME_LENGTH], &[b'e'; MAX_EMAIL_LENGTH])
+    }
+
+    #[test]
+    fn public_api_round_trips_rows() {
+        let path = test_db_path("public_api");
+        let _ = fs::remove_file(&path);
+
+        let mut table = Table::open(&path).unwrap();
+        table.insert(&Row::new(2, b"bob", b"bob@example.com")).unwrap();
+        table.insert(&Row::new(1, b"alice", b"alice@example.com")).unwrap();
+        match table.insert(&Row::new(1, b"eve", b"eve@example.com")) {
+            Err(Error::Execute(ExecuteError::DuplicateKey)) => {}
+            other => panic!("expected a duplicate key error, got {:?}", other),
+        }
+        table.close().unwrap();
+
+        let mut table = Table::open(&path).unwrap();
+        let rows = table.select().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].id, rows[0].username.as_str()), (1, "alice"));
+        assert_eq!((rows[1].id, rows[1].email.as_str()), (2, "bob@example.com"));
+    }
+
+    #[test]
+    fn insert_with_resolves_duplicates() {
+        let mut table = open_test_table("insert_with");
+        let old = Row::new(1, b"old", b"old@example.com");
+        let new = Row::new(1, b"new", b"new@example.com");
+        table.insert_with(&old, |_, _| Resolution::Error).unwrap();
+
+        assert_eq!(
+            table.insert_with(&new, |_, _| Resolution::Error),
+            Err(ExecuteError::DuplicateKey)
+        );
+        assert_eq!(scan(&mut table), vec!["(1, old, old@example.com)"]);
+
+        table
+            .insert_with(&new, |existing, _| {
+                assert_eq!(existing.to_string(), "(1, old, old@example.com)");
+                Resolution::KeepExisting
+            })
+            .unwrap();
+        assert_eq!(scan(&mut table), vec!["(1, old, old@example.com)"]);
+
+        table.insert_with(&new, |_, _| Resolution::UseNew).unwrap();
+        assert_eq!(scan(&mut table), vec!["(1, new, new@example.com)"]);
+    }
+
+    #[test]
+    fn insert_many_matches_one_at_a_time_inserts() {
+        // Out of order, so that some rows land back in leaves already split.
+        let rows: Vec<Row> = (0..120).map(|i| wide_row(i * 37 % 120)).collect();
+        let mut batched = open_test_table("insert_many_batched");
+        batched.insert_many(&rows).unwrap();
+        let mut single = open_test_table("insert_many_single");
+        for row in &rows {
+            single.insert(row).unwrap();
+        }
+
+        assert!(batched.height().unwrap() > 1);
+        assert_eq!(scan(&mut batched), scan(&mut single));
+        let root_page_num = batched.root_page_num;
+        check_parent_pointers(&mut batched, root_page_num);
+    }
+
+    #[test]
+    fn insert_many_stops_at_a_duplicate() {
+        let mut table = open_test_table("insert_many_duplicate");
+        let rows: Vec<Row> = [1, 2, 3, 2, 4].iter().map(|&id| wide_row(id)).collect();
+        match table.insert_many(&rows) {
+            Err(Error::Execute(ExecuteError::DuplicateKey)) => {}
+            other => panic!("expected a duplicate key error, got {:?}", other),
+        }
+        assert_eq!(leaf_keys(&mut table), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn insert_many_descends_less_than_single_inserts() {
+        let rows: Vec<Row> = (1..2001)
+            .map(|id| Row::new(id, b"user", b"user@example.com"))
+            .collect();
+        let mut batched = open_test_table("insert_many_lookups");
+        batched.insert_many(&rows).unwrap();
+        let mut single = open_test_table("single_insert_lookups");
+        for row in &rows {
+            single.insert(row).unwrap();
+        }
+
+        let lookups = |table: &Table| {
+            table.pager.cache_stats.hits + table.pager.cache_stats.misses
+        };
+        assert!(lookups(&batched) < lookups(&single) * 3 / 4);
+    }
+
+    #[test]
+    fn insert_with_rejects_invalid_rows() {
+        let mut table = open_test_table("invalid_rows");
+
+        let non_utf8 = Row::new(1, b"user\xff", b"user@example.com");
+        assert_eq!(
+            table.insert_with(&non_utf8, |_, _| Resolution::Error),
+            Err(ExecuteError::InvalidRow(ParseError::InvalidUtf8))
+        );
+
+        // Rows in the current layout are checked as they are decoded, but
+        // ones from version 1 files are not.
+        let mut bytes = Row::new(2, b"user", b"user@example.com").to_bytes();
+        bytes[size_of::<Key>()] = 200; // username_len
+        assert!(Row::from_bytes(&bytes).is_err());
+        let mut leaf = Node::create_leaf();
+        leaf.insert(0, &Row::new(2, b"user", b"user@example.com")).unwrap();
+        let mut bytes = leaf.to_legacy_bytes();
+        // Variant tag, header, num_cells, the key and the id come before it.
+        bytes[4 + size_of::<bool>() + 4 + 4 + 4 + 4] = 200;
+        let too_long = match Node::from_legacy_bytes(&bytes).unwrap() {
+            Node::Leaf { ref cells, .. } => cells[0].0,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            table.insert_with(&too_long, |_, _| Resolution::Error),
+            Err(ExecuteError::InvalidRow(ParseError::StringTooLong))
+        );
+
+        assert!(scan(&mut table).is_empty());
+    }
+
+    #[test]
+    fn inserts_accept_quoted_fields() {
+        let mut row = Row::default();
+        prepare_insert_into(r#"insert 1 "Ada Lovelace" ada@x.com"#, &mut row).unwrap();
+        assert_eq!(row.to_string(), "(1, Ada Lovelace, ada@x.com)");
+        prepare_insert_into(r#"insert 2  ""   "b  b@x.io" "#, &mut row).unwrap();
+        assert_eq!(row.to_string(), "(2, , b  b@x.io)");
+
+        let quoted_max = format!(r#"insert 3 "{}" a@x.io"#, "a".repeat(MAX_UNAME_LENGTH));
+        assert!(prepare_insert_into(&quoted_max, &mut row).is_ok());
+        let quoted_long = format!(r#"insert 3 " {}" a@x.io"#, "a".repeat(MAX_UNAME_LENGTH));
+        assert_eq!(
+            prepare_insert_into(&quoted_long, &mut row),
+            Err(ParseError::StringTooLong)
+        );
+        for input in &[r#"insert 4 "Ada Lovelace ada@x.com"#, r#"insert 4 "Ada"x ada@x.com"#] {
+            assert_eq!(
+                prepare_insert_into(input, &mut row),
+                Err(ParseError::InvalidSyntax)
+            );
+        }
+    }
+
+    #[test]
+    fn prepare_insert_into_reuses_row() {
+        let mut row = Row::default();
+        prepare_insert_into("insert 1 longusername long@example.com", &mut row).unwrap();
+        assert_eq!(row.to_string(), "(1, longusername, long@example.com)");
+        prepare_insert_into("insert 2 bob b@x.io", &mut row).unwrap();
+        assert_eq!(row.to_string(), "(2, bob, b@x.io)");
+        assert_eq!(row.to_string(), Row::new(2, b"bob", b"b@x.io").to_string());
+    }
+
+    #[test]
+    fn find_ge_seeks_lower_bound() {
+        let mut table = open_test_table("find_ge");
+        for &id in &[10, 20, 30] {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        for &(key, expected) in &[(20, Some(20)), (0, Some(10)), (21, Some(30)), (31, None)] {
+            let mut cursor = table.find_ge(key).unwrap();
+            assert_eq!(cursor.end_of_table, expected.is_none(), "find_ge({})", key);
+            let id = cursor.get_value().unwrap().map(|row| row.id);
+            assert_eq!(id, expected, "find_ge({})", key);
+        }
+    }
+
+    #[test]
+    fn select_page_covers_every_row_once() {
+        let mut table = open_test_table("select_page");
+        let ids: Vec<Key> = (1..41).map(|id| id * 3).collect();
+        for &id in &ids {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let (rows, next) = table.select_page(after, 5).unwrap();
+            assert!(rows.len() <= 5);
+            seen.extend(rows.iter().map(|row| row.id));
+            match next {
+                Some(key) => after = Some(key),
+                None => break,
+            }
+        }
+        assert_eq!(seen, ids);
+
+        let (rows, next) = table.select_page(Some(ids[ids.len() - 1]), 5).unwrap();
+        assert!(rows.is_empty());
+        assert_eq!(next, None);
+
+        for &after in &[None, Some(ids[0])] {
+            let err = table.select_page(after, 0).err().expect("accepted a page size of 0");
+            assert_eq!(err.to_string(), "page size must be at least 1");
+        }
+    }
+
+    #[test]
+    fn successor_and_predecessor() {
+        let mut table = open_test_table("successor");
+        for &id in &[10, 20, 30] {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        let id = |row: Result<Option<Row>, PagerError>| row.unwrap().map(|row| row.id);
+        assert_eq!(id(table.successor(20)), Some(30));
+        assert_eq!(id(table.predecessor(20)), Some(10));
+        assert_eq!(id(table.successor(25)), Some(30));
+        assert_eq!(id(table.predecessor(25)), Some(20));
+        assert_eq!(id(table.successor(30)), None);
+        assert_eq!(id(table.predecessor(30)), Some(20));
+        assert_eq!(id(table.predecessor(10)), None);
+        assert_eq!(id(table.successor(10)), Some(20));
+    }
+
+    #[test]
+    fn metadata_page_precedes_data() {
+        let path = test_db_path("meta_page");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        execute_insert(&Row::new(1, b"user1", b"person1@example.com"), &mut table).unwrap();
+        db_close(&mut table).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        let meta = Meta::from_bytes(&bytes[..PAGE_SIZE]).unwrap();
+        assert_eq!(meta, Meta::new(meta::DEFAULT_ROOT_PAGE_NUM, 2));
+        let email_at = bytes
+            .windows(b"person1@example.com".len())
+            .position(|w| w == b"person1@example.com")
+            .unwrap();
+        assert!(email_at >= PAGE_SIZE, "row data found in page 0");
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.root_page_num, meta::DEFAULT_ROOT_PAGE_NUM);
+        assert_eq!(scan(&mut table), vec!["(1, user1, person1@example.com)"]);
+    }
+
+    #[test]
+    fn pages_that_fail_their_checksum_are_refused() {
+        let path = test_db_path("checksums");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        for id in 1..41 {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        db_close(&mut table).unwrap();
+
+        // A flipped byte in a row still decodes; only the checksum notices.
+        let mut bytes = fs::read(&path).unwrap();
+        let page_num = 2;
+        let at = page_num * PAGE_SIZE
+            + bytes[page_num * PAGE_SIZE..]
+                .iter()
+                .position(|&b| b == b'u')
+                .unwrap();
+        bytes[at] = b'v';
+        fs::write(&path, &bytes).unwrap();
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(
+            table.pager.get_page(page_num).err(),
+            Some(PagerError::Corrupt { page_num })
+        );
+
+        // Without its root, the table can't be opened at all.
+        bytes.truncate(PAGE_SIZE + 20);
+        fs::write(&path, &bytes).unwrap();
+        let err = db_open(&path).err().expect("opened a table with a torn root");
+        assert_eq!(err.to_string(), "page 1 is torn or corrupt");
+    }
+
+    /// A leaf holding row 7, laid out as in a version 1 file.
+    fn legacy_leaf_bytes() -> Vec<u8> {
+        let mut leaf = Node::create_leaf();
+        leaf.insert(0, &Row::new(7, b"old", b"old@example.com")).unwrap();
+        leaf.to_legacy_bytes()
+    }
+
+    #[test]
+    fn headerless_files_are_migrated() {
+        let path = test_db_path("headerless");
+        fs::write(&path, legacy_leaf_bytes()).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.root_page_num, meta::DEFAULT_ROOT_PAGE_NUM);
+        assert_eq!(scan(&mut table), vec!["(7, old, old@example.com)"]);
+        db_close(&mut table).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        assert!(Meta::from_bytes(&bytes[..PAGE_SIZE]).is_some());
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(scan(&mut table), vec!["(7, old, old@example.com)"]);
+    }
+
+    #[test]
+    fn unrelated_files_are_rejected() {
+        let path = test_db_path("unrelated");
+        fs::write(&path, "id,username,email\n1,user,user@example.com\n".repeat(200)).unwrap();
+        let err = db_open(&path).err().expect("opened a file that isn't a database");
+        assert_eq!(err.to_string(), "not a valid database file");
+    }
+
+    #[test]
+    fn newer_format_versions_are_rejected() {
+        let path = test_db_path("newer_version");
+        let mut meta = Meta::new(meta::DEFAULT_ROOT_PAGE_NUM, 2);
+        meta.version = meta::FORMAT_VERSION + 1;
+        let mut bytes = meta.to_bytes();
+        bytes.resize(2 * PAGE_SIZE, 0);
+        fs::write(&path, bytes).unwrap();
+
+        let err = db_open(&path).err().expect("opened a file from a newer version");
+        assert_eq!(
+            err.to_string(),
+            format!("unsupported format version {}", meta::FORMAT_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn wrong_keys_are_rejected_even_at_the_same_length() {
+        let path = test_db_path("wrong_key");
+        let halves = format!("{}{}", "x".repeat(32), "y".repeat(32));
+        let swapped = format!("{}{}", "y".repeat(32), "x".repeat(32));
+        let long_a = "a".repeat(64);
+        let long_b = "b".repeat(64);
+        for &(key, wrong) in &[
+            ("hunter2", "hunter3"),
+            ("hunter2", "hunter2\0"),
+            (&long_a[..], &long_b[..]),
+            (&halves[..], &swapped[..]),
+        ] {
+            let _ = fs::remove_file(&path);
+            let mut table = db_open_with_key(&path, Some(key)).unwrap();
+            table.insert(&wide_row(1)).unwrap();
+            db_close(&mut table).unwrap();
+
+            let err = db_open_with_key(&path, Some(wrong)).err().expect("opened with a wrong key");
+            assert_eq!(err.to_string(), "wrong key for encrypted database", "{:?}", wrong);
+            let mut table = db_open_with_key(&path, Some(key)).unwrap();
+            assert_eq!(scan(&mut table), vec![wide_row(1).to_string()]);
+        }
+    }
+
+    #[test]
+    fn files_with_the_other_key_width_are_rejected() {
+        let path = test_db_path("other_key_width");
+        let mut meta = Meta::new(meta::DEFAULT_ROOT_PAGE_NUM, 2);
+        let (ours, theirs) = if size_of::<Key>() == 4 { (32, 64) } else { (64, 32) };
+        meta.key_size = theirs / 8;
+        let mut bytes = meta.to_bytes();
+        bytes.resize(2 * PAGE_SIZE, 0);
+        fs::write(&path, bytes).unwrap();
+
+        let err = db_open(&path).err().expect("opened a file with the other key width");
+        assert_eq!(
+            err.to_string(),
+            format!("database has {}-bit ids, but this build uses {}-bit ids", theirs, ours)
+        );
+    }
+
+    #[test]
+    fn select_stream_visits_rows_in_order_and_stops() {
+        let mut table = open_test_table("select_stream");
+        for &id in &[3, 1, 2] {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        let mut seen = Vec::new();
+        table.select_stream(|row| {
+            seen.push(row.id);
+            Scan::Continue
+        }).unwrap();
+        assert_eq!(seen, vec![1, 2, 3]);
+
+        seen.clear();
+        table.select_stream(|row| {
+            seen.push(row.id);
+            if row.id == 2 {
+                Scan::Stop
+            } else {
+                Scan::Continue
+            }
+        }).unwrap();
+        assert_eq!(seen, vec![1, 2]);
+    }
+
+    #[test]
+    fn get_value_past_last_cell_is_none() {
+        let mut table = open_test_table("get_value_none");
+        assert!(table.find(0).unwrap().get_value().unwrap().is_none());
+        execute_insert(&Row::new(1, b"user", b"user@example.com"), &mut table).unwrap();
+        assert_eq!(table.find(1).unwrap().get_value().unwrap().map(|row| row.id), Some(1));
+        assert!(table.find(2).unwrap().get_value().unwrap().is_none());
+        // Id 0 matches a default cell's key, so it must not be mistaken for a duplicate.
+        execute_insert(&Row::new(0, b"zero", b"zero@example.com"), &mut table).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "wide-keys")]
+    fn wide_keys_round_trip_and_order() {
+        let big = u32::MAX as Key + 1;
+        let mut table = open_test_table("wide_keys");
+        for &id in &[big + 10, 5, big] {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        match prepare_statement("insert 4294967297 parsed parsed@example.com") {
+            Ok(Statement::Insert(row)) => execute_insert(&row, &mut table).unwrap(),
+            _ => panic!("could not parse a wide id"),
+        }
+        let expected = vec![
+            "(5, user, user@example.com)",
+            "(4294967296, user, user@example.com)",
+            "(4294967297, parsed, parsed@example.com)",
+            "(4294967306, user, user@example.com)",
+        ];
+        assert_eq!(scan(&mut table), expected);
+        db_close(&mut table).unwrap();
+
+        let mut reopened = db_open(&test_db_path("wide_keys")).unwrap();
+        assert_eq!(scan(&mut reopened), expected);
+    }
+
+    #[test]
+    fn page_type_counts_single_leaf() {
+        let mut table = open_test_table("page_type_counts");
+        execute_insert(&Row::new(1, b"user", b"user@example.com"), &mut table).unwrap();
+        assert_eq!(table.page_type_counts(), Ok((1, 0)));
+    }
+
+    #[test]
+    fn page_type_counts_after_the_first_split() {
+        let mut table = open_test_table("page_type_counts_split");
+        let mut id = 0;
+        while table.height().unwrap() == 1 {
+            id += 1;
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        assert_eq!(table.page_type_counts(), Ok((2, 1)));
+    }
+
+    #[test]
+    fn estimate_count_is_close_to_exact_count() {
+        let mut table = open_test_table("estimate_count");
+        let ids: Vec<Key> = (1..13).map(|i| i * 3).collect();
+        for &id in &ids {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        for &(lo, hi) in &[(0, 100), (3, 3), (4, 5), (10, 20), (30, 2), (36, 40)] {
+            let exact = ids.iter().filter(|&&id| lo <= id && id <= hi).count() as u64;
+            let estimate = table.estimate_count(lo, hi).unwrap();
+            let tolerance = 1 + exact / 4;
+            assert!(
+                estimate + tolerance >= exact && estimate <= exact + tolerance,
+                "[{}, {}]: estimated {}, exact {}",
+                lo,
+                hi,
+                estimate,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn estimate_count_is_close_across_subtrees() {
+        let mut table = open_test_table("estimate_count_deep");
+        // Scattered inserts leave the leaves unevenly filled.
+        for i in 1..101 {
+            table.insert(&wide_row(i * 37 % 101)).unwrap();
+        }
+        assert!(table.height().unwrap() >= 3);
+        for &(lo, hi) in &[(0, 200), (1, 100), (5, 95), (10, 60), (40, 80), (30, 31)] {
+            let exact = (cmp::max(lo, 1)..cmp::min(hi, 100) + 1).count() as u64;
+            let estimate = table.estimate_count(lo, hi).unwrap();
+            let tolerance = 1 + exact / 4;
+            assert!(
+                estimate + tolerance >= exact && estimate <= exact + tolerance,
+                "[{}, {}]: estimated {}, exact {}",
+                lo,
+                hi,
+                estimate,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn add_page_goes_past_a_partial_last_page() {
+        // Version 1 files end wherever the root leaf does.
+        let path = test_db_path("add_page");
+        let bytes = legacy_leaf_bytes();
+        assert!(bytes.len() < PAGE_SIZE);
+        fs::write(&path, bytes).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        let root_page_num = table.root_page_num;
+        let new_page_num = table.pager.add_page(Box::new(Node::create_leaf()));
+        assert_eq!(new_page_num, Ok(root_page_num + 1));
+        assert_eq!(scan(&mut table), vec!["(7, old, old@example.com)"]);
+
+        table.pager.max_pages = 100;
+        while table.pager.get_unused_page_num() < 100 {
+            table.pager.add_page(Box::new(Node::create_leaf())).unwrap();
+        }
+        assert_eq!(
+            table.pager.add_page(Box::new(Node::create_leaf())),
+            Err(PagerError::OutOfBounds {
+                page_num: 100,
+                max_pages: 100,
+            })
+        );
+    }
+
+    #[test]
+    fn get_page_rejects_page_numbers_past_the_end() {
+        let mut table = open_test_table("get_page_bounds");
+        let out_of_bounds = || {
+            Err(PagerError::OutOfBounds {
+                page_num: TABLE_MAX_PAGES,
+                max_pages: TABLE_MAX_PAGES,
+            })
+        };
+        assert_eq!(table.pager.get_page(TABLE_MAX_PAGES).map(|_| ()), out_of_bounds());
+        assert_eq!(table.pager.get_page_mut(TABLE_MAX_PAGES).map(|_| ()), out_of_bounds());
+        assert!(table.pager.get_page(TABLE_MAX_PAGES - 1).is_ok());
+    }
+
+    /// Hangs two leaves, holding `left` and `right`, off an internal root.
+    fn two_level_table(name: &str, left: &[Key], right: &[Key]) -> Table {
+        let mut table = open_test_table(name);
+        let root_page_num = table.root_page_num as usize;
+        table.pager.get_page(root_page_num).unwrap();
+        let mut leaves = Vec::new();
+        for ids in &[left, right] {
+            let mut leaf = Node::create_leaf();
+            for (i, &id) in ids.iter().enumerate() {
+                leaf.insert(i as u32, &Row::new(id, b"user", b"user@example.com")).unwrap();
+            }
+            leaves.push(table.pager.add_page(Box::new(leaf)).unwrap());
+        }
+        let root = Node::create_internal(leaves[0], left[left.len() - 1], leaves[1]);
+        *table.pager.get_page_mut(root_page_num).unwrap() = root;
+        table
+    }
+
+    #[test]
+    fn find_descends_through_internal_nodes() {
+        let mut table = two_level_table("internal_find", &[1, 2, 3], &[10, 11, 12]);
+        let root_page_num = table.root_page_num as usize;
+        let children = table.pager.get_page(root_page_num).unwrap().children();
+        let (left, right) = (children[0], children[1]);
+        let expected = [(2, left, 1), (3, left, 2), (5, right, 0), (12, right, 2)];
+        for &(key, page_num, cell_num) in &expected {
+            let cursor = table.find(key).unwrap();
+            assert_eq!((cursor.page_num, cursor.cell_num), (page_num, cell_num), "find({})", key);
+        }
+        assert_eq!(table.start().unwrap().page_num, left);
+        let end = table.end().unwrap();
+        assert_eq!((end.page_num, end.cell_num), (right, 3));
+        let mut cursor = table.find_ge(11).unwrap();
+        assert_eq!(cursor.get_value().unwrap().map(|row| row.id), Some(11));
+
+        assert_eq!(table.page_type_counts(), Ok((2, 1)));
+        assert_eq!(table.tree_stats().unwrap().height, 2);
+        assert_eq!(table.estimate_count(2, 11), Ok(4));
+        assert_eq!(
+            table.tree_to_json().unwrap(),
+            format!(
+                "{{\"type\":\"internal\",\"page\":1,\"keys\":[3],\"children\":[\
+                 {{\"type\":\"leaf\",\"page\":{},\"keys\":[1,2,3],\"children\":[]}},\
+                 {{\"type\":\"leaf\",\"page\":{},\"keys\":[10,11,12],\"children\":[]}}]}}",
+                left, right
+            )
+        );
+    }
+
+    #[test]
+    fn write_tree_indents_each_level() {
+        let mut table = two_level_table("write_tree", &[1, 2], &[10]);
+        let root_page_num = table.root_page_num;
+        let mut tree = String::new();
+        table.write_tree(root_page_num, 1, &mut tree).unwrap();
+        let expected = [
+            "internal (size 1)",
+            "  - leaf (size 2)",
+            "    - 0 : 1",
+            "    - 1 : 2",
+            "  - key 2",
+            "  - leaf (size 1)",
+            "    - 0 : 10",
+        ];
+        assert_eq!(tree, expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn find_stops_at_a_pointer_cycle() {
+        let mut table = open_test_table("internal_cycle");
+        let root_page_num = table.root_page_num;
+        *table.pager.get_page_mut(root_page_num as usize).unwrap() =
+            Node::create_internal(root_page_num, 10, root_page_num);
+        let corrupt = || PagerError::Corrupt {
+            page_num: root_page_num as usize,
+        };
+        assert_eq!(table.find(1).err(), Some(corrupt()));
+        let statement = prepare_statement("select").unwrap();
+        assert_eq!(
+            execute_statement(statement, &mut table, None).err(),
+            Some(ExecuteError::Pager(corrupt()))
+        );
+    }
+
+    /// The keys of every leaf, left to right.
+    fn leaf_keys(table: &mut Table) -> Vec<Key> {
+        let mut keys = Vec::new();
+        table.walk(&mut |node, _| {
+            if let &Node::Leaf { .. } = node {
+                keys.extend(node.keys());
+            }
+        }).unwrap();
+        keys
+    }
+
+    #[test]
+    fn relink_repairs_a_broken_sibling_chain() {
+        let mut table = open_test_table("relink");
+        for id in 1..60 {
+            table.insert(&wide_row(id)).unwrap();
+        }
+        let expected = scan(&mut table);
+        assert_eq!(table.relink().unwrap(), 0);
+
+        let leaves = table.leaf_pages().unwrap();
+        assert!(leaves.len() > 3);
+        // End the chain early, and make another link skip a leaf.
+        for &(page_num, next) in &[(leaves[0], 0), (leaves[1], leaves[3])] {
+            if let &mut Node::Leaf {
+                ref mut next_leaf, ..
+            } = table.pager.get_page_mut(page_num as usize).unwrap()
+            {
+                *next_leaf = next;
+            }
+        }
+        let first_leaf_rows = table.pager.get_page(leaves[0] as usize).unwrap().keys().len();
+        assert_eq!(scan(&mut table), &expected[..first_leaf_rows]);
+
+        assert_eq!(table.relink().unwrap(), 2);
+        assert_eq!(scan(&mut table), expected);
+    }
+
+    /// Panics unless every node below `page_num` points back at its parent
+    /// and only the root is flagged as one.
+    fn check_parent_pointers(table: &mut Table, page_num: u32) {
+        let is_root = page_num == table.root_page_num;
+        let children = {
+            let node = table.pager.get_page(page_num as usize).unwrap();
+            assert_eq!(node.header().is_root, is_root, "page {}", page_num);
+            node.children()
+        };
+        for child in children {
+            let parent_ptr = table.pager.get_page(child as usize).unwrap().header().parent_ptr;
+            assert_eq!(parent_ptr, page_num, "parent of page {}", child);
+            check_parent_pointers(table, child);
+        }
+    }
+
+    #[test]
+    fn sequential_inserts_grow_the_tree() {
+        let path = test_db_path("grow_tree");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        let root_page_num = table.root_page_num;
+        let ids: Vec<Key> = (1..101).collect();
+        for &id in &ids {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        let height = table.height().unwrap();
+        assert!(height >= 3, "height {}", height);
+        assert_eq!(table.root_page_num, root_page_num);
+        assert_eq!(leaf_keys(&mut table), ids);
+        check_parent_pointers(&mut table, root_page_num);
+        for &id in &ids {
+            assert_eq!(table.find(id).unwrap().get_value().unwrap().map(|row| row.id), Some(id));
+        }
+        db_close(&mut table).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.root_page_num, root_page_num);
+        assert_eq!(leaf_keys(&mut table), ids);
+    }
+
+    #[test]
+    fn inserts_report_table_full_when_pages_run_out() {
+        let mut table = open_test_table("pages_run_out");
+        table.pager.max_pages = 100;
+        let mut inserted = Vec::new();
+        for id in 1.. {
+            match execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table) {
+                Ok(()) => inserted.push(id),
+                Err(e) => {
+                    assert_eq!(e, ExecuteError::TableFull);
+                    break;
+                }
+            }
+        }
+        assert!(table.height().unwrap() > 1);
+        // The rejected insert left the tree as it was.
+        assert_eq!(leaf_keys(&mut table), inserted);
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+    }
+
+    #[test]
+    fn tables_grow_past_a_hundred_pages() {
+        let path = test_db_path("many_pages");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        let ids: Vec<Key> = (1..1501).collect();
+        for &id in &ids {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        assert!(table.pager.num_pages > 100, "{} pages", table.pager.num_pages);
+        db_close(&mut table).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(leaf_keys(&mut table), ids);
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+    }
+
+    #[test]
+    fn resident_bytes_count_a_leafs_cells() {
+        let mut table = open_test_table("resident_bytes");
+        for id in 1..151 {
+            table.insert(&Row::new(id, b"u", b"e")).unwrap();
+        }
+        assert_eq!(table.height().unwrap(), 1);
+        assert_eq!(table.pager.resident_pages(), 1);
+        assert_eq!(
+            table.pager.resident_bytes(),
+            size_of::<Node>() + 150 * size_of::<btree::Cell>()
+        );
+    }
+
+    #[test]
+    fn small_caches_evict_and_reload_pages() {
+        let path = test_db_path("small_cache");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        table.pager.cache_size = 8;
+        let ids: Vec<Key> = (1..301).rev().collect();
+        for &id in &ids {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        assert!(table.pager.resident_pages() <= 8);
+        assert!(table.pager.cache_stats.evictions > 0);
+        let mut sorted = ids.clone();
+        sorted.sort();
+        assert_eq!(leaf_keys(&mut table), sorted);
+        assert!(table.pager.resident_pages() <= 8);
+        db_close(&mut table).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(leaf_keys(&mut table), sorted);
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+    }
+
+    #[test]
+    fn cursors_cross_leaf_boundaries() {
+        let mut table = open_test_table("cross_leaves");
+        let ids: Vec<Key> = (1..61).map(|i| i * 2).collect();
+        for &id in ids.iter().rev() {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        assert!(table.page_type_counts().unwrap().0 > 2);
+        let selected: Vec<Key> = table.select().unwrap().iter().map(|row| row.id).collect();
+        assert_eq!(selected, ids);
+        assert_eq!(scan(&mut table).len(), ids.len());
+
+        let id = |row: Result<Option<Row>, PagerError>| row.unwrap().map(|row| row.id);
+        for pair in ids.windows(2) {
+            assert_eq!(id(table.successor(pair[0])), Some(pair[1]));
+            assert_eq!(id(table.predecessor(pair[1])), Some(pair[0]));
+        }
+        let mut backwards = Vec::new();
+        {
+            let mut cursor = table.end().unwrap();
+            while cursor.retreat().unwrap() {
+                backwards.push(cursor.get_value().unwrap().unwrap().id);
+            }
+        }
+        backwards.reverse();
+        assert_eq!(backwards, ids);
+
+        // Empties whole leaves in the middle of the sibling chain.
+        let range = RangeBound {
+            lower: Some(KeyBound {
+                key: 10,
+                inclusive: true,
+            }),
+            upper: Some(KeyBound {
+                key: 100,
+                inclusive: false,
+            }),
+        };
+        let remaining: Vec<Key> = ids.iter().cloned().filter(|&id| id < 10 || id >= 100).collect();
+        assert_eq!(table.delete_range(&range), Ok(ids.len() - remaining.len()));
+        let selected: Vec<Key> = table.select().unwrap().iter().map(|row| row.id).collect();
+        assert_eq!(selected, remaining);
+        let mut cursor = table.find_ge(50).unwrap();
+        assert_eq!(cursor.get_value().unwrap().map(|row| row.id), Some(100));
+        assert_eq!(id(table.predecessor(100)), Some(8));
+    }
+
+    #[test]
+    fn statements_return_their_rows() {
+        let mut table = open_test_table("statement_output");
+        for &(id, name) in &[(1, "carol"), (2, "alice"), (3, "bob")] {
+            execute_insert(&Row::new(id, name.as_bytes(), b"x@example.com"), &mut table).unwrap();
+        }
+        let mut run = |input: &str, scan_budget| {
+            let statement = prepare_statement(input).unwrap();
+            match execute_statement(statement, &mut table, scan_budget).unwrap() {
+                Output::Done => (vec![], false),
+                Output::Rows(rows) => (rows.iter().map(|row| row.id).collect(), false),
+                Output::PartialRows(rows) => (rows.iter().map(|row| row.id).collect(), true),
+                Output::Deleted(count) => (vec![count as Key], false),
+            }
+        };
+        assert_eq!(run("select", None), (vec![1, 2, 3], false));
+        assert_eq!(run("select order by username", None), (vec![2, 3, 1], false));
+        assert_eq!(run("select order by id desc", None), (vec![3, 2, 1], false));
+        assert_eq!(run("select", Some(2)), (vec![1, 2], true));
+        assert_eq!(run("select 2", None), (vec![2], false));
+        assert_eq!(run("select 2 3", None), (vec![2, 3], false));
+        assert_eq!(run("select 9", None), (vec![], false));
+        assert_eq!(run("delete where id >= 3", None), (vec![1], false));
+        assert_eq!(run("insert 4 dave d@example.com", None), (vec![], false));
+    }
+
+    #[test]
+    fn delete_removes_one_row() {
+        let mut table = open_test_table("delete");
+        for &id in &[1, 2, 3] {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        match prepare_statement("delete 2") {
+            Ok(statement) => {
+                execute_statement(statement, &mut table, None).unwrap();
+            }
+            Err(e) => panic!("could not parse delete: {:?}", e),
+        }
+        let selected: Vec<Key> = table.select().unwrap().iter().map(|row| row.id).collect();
+        assert_eq!(selected, vec![1, 3]);
+        assert_eq!(table.delete(2).map(|row| row.id), Err(ExecuteError::NoSuchKey));
+        assert_eq!(table.delete(4).map(|row| row.id), Err(ExecuteError::NoSuchKey));
+        assert_eq!(prepare_statement("delete -1").err(), Some(ParseError::NegativeID));
+        assert_eq!(prepare_statement("delete 1 2").err(), Some(ParseError::InvalidSyntax));
+    }
+
+    #[test]
+    fn update_overwrites_an_existing_row() {
+        let mut table = open_test_table("update");
+        execute_insert(&Row::new(1, b"old", b"old@example.com"), &mut table).unwrap();
+        let mut run = |input: &str| match prepare_statement(input) {
+            Ok(statement) => execute_statement(statement, &mut table, None).map(|_| ()),
+            Err(e) => panic!("could not parse {:?}: {:?}", input, e),
+        };
+        assert_eq!(run("update 1 new new@example.com"), Ok(()));
+        assert_eq!(run("update 2 new new@example.com"), Err(ExecuteError::NoSuchKey));
+        assert_eq!(scan(&mut table), vec!["(1, new, new@example.com)"]);
+
+        let long_name = format!("update 1 {} a@b.c", "a".repeat(MAX_UNAME_LENGTH + 1));
+        assert_eq!(prepare_statement(&long_name).err(), Some(ParseError::StringTooLong));
+        assert_eq!(prepare_statement("update 1 new").err(), Some(ParseError::InvalidSyntax));
+    }
+
+    #[test]
+    fn updates_that_outgrow_their_leaf_split_it() {
+        let mut table = open_test_table("update_grows");
+        // Short rows, nearly filling the root leaf.
+        let short_len = Row::new(0, b"u", b"u@x").encoded_len();
+        let ids: Vec<Key> = (1..((PAGE_SIZE - 100) / short_len) as Key).collect();
+        for &id in &ids {
+            execute_insert(&Row::new(id, b"u", b"u@x"), &mut table).unwrap();
+        }
+        assert_eq!(table.height(), Ok(1));
+
+        execute_update(&wide_row(100), &mut table).unwrap();
+        assert_eq!(table.height(), Ok(2));
+        assert_eq!(leaf_keys(&mut table), ids);
+        let row = table.get(100).unwrap().map(|row| row.to_string());
+        assert_eq!(row, Some(wide_row(100).to_string()));
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+        table.walk(&mut |node, _| assert!(node.encoded_len() <= PAGE_SIZE)).unwrap();
+    }
+
+    #[test]
+    fn select_one_looks_up_a_single_row() {
+        let mut table = open_test_table("select_one");
+        for id in 1..41 {
+            execute_insert(&Row::new(id * 2, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        assert_eq!(table.get(40).unwrap().map(|row| row.id), Some(40));
+        assert_eq!(table.get(41).unwrap().map(|row| row.id), None);
+        assert_eq!(table.get(0).unwrap().map(|row| row.id), None);
+        assert_eq!(table.get(100).unwrap().map(|row| row.id), None);
+
+        match prepare_statement("select 40") {
+            Ok(Statement::SelectOne(40)) => {}
+            _ => panic!("could not parse select 40"),
+        }
+        assert_eq!(prepare_statement("select -1").err(), Some(ParseError::NegativeID));
+        assert_eq!(prepare_statement("select x").err(), Some(ParseError::InvalidSyntax));
+    }
+
+    #[test]
+    fn select_range_crosses_leaves_and_stops_at_the_upper_bound() {
+        let mut table = open_test_table("select_range");
+        for id in 1..51 {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        let (lo, hi) = match prepare_statement("select 10 20") {
+            Ok(Statement::SelectRange(lo, hi)) => (lo, hi),
+            _ => panic!("could not parse select 10 20"),
+        };
+        let mut ids = Vec::new();
+        table
+            .select_range(&RangeBound::between(lo, hi), |row| ids.push(row.id))
+            .unwrap();
+        assert_eq!(ids, (10..21).collect::<Vec<Key>>());
+
+        assert_eq!(prepare_statement("select 20 10").err(), Some(ParseError::InvalidSyntax));
+        assert_eq!(prepare_statement("select 1 x").err(), Some(ParseError::InvalidSyntax));
+    }
+
+    #[test]
+    fn emptied_leaves_are_freed_and_reused() {
+        let path = test_db_path("free_list");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        let ids: Vec<Key> = (1..81).collect();
+        for &id in &ids {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        let range = RangeBound::between(20, 60);
+        assert_eq!(table.delete_range(&range), Ok(41));
+        let remaining: Vec<Key> = ids.iter().cloned().filter(|&id| id < 20 || id > 60).collect();
+        assert_eq!(leaf_keys(&mut table), remaining);
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+        let free_pages = table.pager.free_pages.clone();
+        assert!(!free_pages.is_empty());
+        db_close(&mut table).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.pager.free_pages, free_pages);
+        let num_pages = table.pager.num_pages;
+        for id in 20..61 {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        assert_eq!(table.pager.num_pages, num_pages);
+        assert!(table.pager.free_pages.len() < free_pages.len());
+        assert_eq!(leaf_keys(&mut table), ids);
+        check_parent_pointers(&mut table, root_page_num);
+
+        // Emptying the whole table leaves just an empty root leaf.
+        let everything = RangeBound::between(0, 100);
+        assert_eq!(table.delete_range(&everything), Ok(ids.len()));
+        assert_eq!(table.is_empty(), Ok(true));
+        assert_eq!(table.pager.free_pages.len(), table.pager.num_pages - 2);
+    }
+
+    #[test]
+    fn only_changed_pages_are_written_back() {
+        let path = test_db_path("dirty_pages");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        for id in 1..15 {
+            execute_insert(&wide_row(id), &mut table).unwrap();
+        }
+        db_close(&mut table).unwrap();
+        // The root was a full leaf before it split, but none of its rows are left.
+        let bytes = fs::read(&path).unwrap();
+        let root = &bytes[PAGE_SIZE..2 * PAGE_SIZE];
+        let email = [b'e'; MAX_EMAIL_LENGTH];
+        assert!(!root.windows(email.len()).any(|w| w == &email[..]));
+
+        let mut table = db_open(&path).unwrap();
+        let root_page_num = table.root_page_num as usize;
+        assert_eq!(scan(&mut table).len(), 14);
+        assert!(table.pager.dirty.is_empty());
+        table.delete(14).unwrap();
+        let right_page_num = table.find(13).unwrap().page_num as usize;
+        let dirty: Vec<usize> = table.pager.dirty.iter().cloned().collect();
+        assert_eq!(dirty, vec![right_page_num]);
+        assert_ne!(right_page_num, root_page_num);
+        db_close(&mut table).unwrap();
+        assert!(table.pager.dirty.is_empty());
+    }
+
+    #[test]
+    fn freed_pages_at_the_end_are_truncated_on_close() {
+        let path = test_db_path("truncate_free_tail");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        for id in 1..60 {
+            table.insert(&wide_row(id)).unwrap();
+        }
+        db_close(&mut table).unwrap();
+        let full_len = fs::metadata(&path).unwrap().len();
+
+        let mut table = db_open(&path).unwrap();
+        execute_delete_where(&mut table, RangeBound::between(30, 59)).unwrap();
+        db_close(&mut table).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < full_len);
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(leaf_keys(&mut table), (1..30).collect::<Vec<Key>>());
+        for id in 30..60 {
+            table.insert(&wide_row(id)).unwrap();
+        }
+        assert_eq!(leaf_keys(&mut table), (1..60).collect::<Vec<Key>>());
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+    }
+
+    #[test]
+    fn deleting_a_range_across_leaves_keeps_the_tree_intact() {
+        let mut table = open_test_table("delete_across_leaves");
+        for id in 1..100 {
+            table.insert(&wide_row(id)).unwrap();
+        }
+        let leaves_before = table.leaf_pages().unwrap().len();
+        assert_eq!(
+            execute_delete_where(&mut table, RangeBound::between(20, 70)).unwrap(),
+            51
+        );
+
+        let remaining: Vec<Key> = (1..20).chain(71..100).collect();
+        assert_eq!(leaf_keys(&mut table), remaining);
+        let rows: Vec<String> = remaining.iter().map(|&id| wide_row(id).to_string()).collect();
+        assert_eq!(scan(&mut table), rows);
+        assert!(table.leaf_pages().unwrap().len() < leaves_before);
+        assert_eq!(table.relink().unwrap(), 0);
+        let root_page_num = table.root_page_num;
+        check_parent_pointers(&mut table, root_page_num);
+        for id in 1..100 {
+            let found = table.find(id).unwrap().get_value().unwrap().map(|row| row.id);
+            assert_eq!(found == Some(id), !(20..=70).contains(&id), "id {}", id);
+        }
+
+        for id in 20..71 {
+            table.insert(&wide_row(id)).unwrap();
+        }
+        assert_eq!(leaf_keys(&mut table), (1..100).collect::<Vec<Key>>());
+        check_parent_pointers(&mut table, root_page_num);
+    }
+
+    #[test]
+    fn page_count_survives_preallocation() {
+        let path = test_db_path("page_count");
+        let _ = fs::remove_file(&path);
+        let mut table = db_open(&path).unwrap();
+        execute_insert(&Row::new(1, b"user", b"user@example.com"), &mut table).unwrap();
+        table.pager.preallocate(20).unwrap();
+        db_close(&mut table).unwrap();
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.pager.get_unused_page_num(), 2);
+        for id in 2..60 {
+            execute_insert(&Row::new(id, b"user", b"user@example.com"), &mut table).unwrap();
+        }
+        let num_pages = table.pager.num_pages;
+        db_close(&mut table).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 20 * PAGE_SIZE as u64);
+
+        let mut table = db_open(&path).unwrap();
+        assert_eq!(table.pager.num_pages, num_pages);
+        assert_eq!(scan(&mut table).len(), 59);
+    }
+}
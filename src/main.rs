@@ -1,262 +1,46 @@
 #![feature(const_size_of)]
 
 extern crate bincode;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-
-mod btree;
-mod serde_ext;
+extern crate db_tutorial;
 
 use std::env;
-use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
-use std::mem::{align_of, size_of};
-
-use bincode::{deserialize_from, serialize_into, Infinite};
-
-use btree::{Node, Row, PAGE_SIZE};
-
-const MAX_UNAME_LENGTH: usize = 32;
-const MAX_EMAIL_LENGTH: usize = 255;
-
-#[derive(Debug)]
-enum PagerError {
-    OutOfBounds { page_num: usize, max_pages: usize },
-    CouldNotRead,
-}
-
-struct Cursor<'a> {
-    table: &'a mut Table,
-    page_num: u32,
-    cell_num: u32,
-    end_of_table: bool,
-}
-
-impl<'a> Cursor<'a> {
-    fn get_value(&mut self) -> &Row {
-        let page_num = self.page_num as usize;
-        let page = self.table.pager.get_page(page_num).unwrap();
-        match page {
-            &Node::Leaf { ref cells, .. } => &(cells[self.cell_num as usize].1),
-            _ => unimplemented!("Internal node"),
-        }
-    }
-
-    fn set_value(&mut self, val: &Row) {
-        let page_num = self.page_num as usize;
-        let page = self.table.pager.get_page_mut(page_num).unwrap();
-        match page {
-            &mut Node::Leaf { ref mut cells, .. } => {
-                cells[self.cell_num as usize].set_val(val);
-            }
-            _ => unimplemented!("Internal node"),
-        }
-    }
-
-    fn advance(&mut self) {
-        let page_num = self.page_num as usize;
-        let node = self.table.pager.get_page(page_num).unwrap();
-        self.cell_num += 1;
-        match node {
-            &Node::Leaf { num_cells, .. } if self.cell_num >= num_cells => {
-                self.end_of_table = true;
-            }
-            _ => {}
-        }
-    }
-
-    fn insert(&mut self, key: u32, val: &Row) {
-        let page_num = self.page_num as usize;
-        let page = self.table.pager.get_page_mut(page_num).unwrap();
-        page.insert(self.cell_num, key, val);
-    }
-}
-
-struct Pager {
-    fd: File,
-    file_size: u64,
-    pages: [Option<Box<Node>>; TABLE_MAX_PAGES],
-    num_pages: usize,
-}
-
-impl Pager {
-    fn new(f: File, file_size: u64) -> Self {
-        Pager {
-            fd: f,
-            file_size,
-            pages: {
-                let mut array: [Option<Box<Node>>; 100] = unsafe { std::mem::uninitialized() };
-                for i in array.iter_mut() {
-                    unsafe {
-                        ::std::ptr::write(i, None);
-                    }
-                }
-                array
-            },
-            num_pages: file_size as usize / PAGE_SIZE,
-        }
-    }
+use std::mem::size_of;
 
-    fn open(filename: &str) -> Result<Box<Self>, io::Error> {
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(filename)?;
-        let file_size = f.seek(io::SeekFrom::End(0))
-            .expect("Could not read to end of file");
-        let pager = Box::new(Pager::new(f, file_size));
-        Ok(pager)
-    }
+use bincode::{serialize, Infinite};
 
-    // fn read_into_row<R: Read>(&self, reader: &mut R, row: &mut Row) -> io::Result<()> {
-    //     std::mem::replace(row, deserialize_from(reader, Infinite));
-    //     Ok(())
-    // }
+use db_tutorial::btree::{self, Key, Node, Row, PAGE_SIZE};
+use db_tutorial::{csv, dump, fuzz, meta};
+use db_tutorial::{db_close, db_open_with_key, execute_statement, prepare_insert_into,
+                  prepare_statement, CacheStats, ExecuteError, Output, ParseError, Statement,
+                  DEFAULT_CACHE_SIZE, MAX_EMAIL_LENGTH, MAX_UNAME_LENGTH};
 
-    fn get_page<'a>(&'a mut self, page_num: usize) -> Result<&'a Node, PagerError> {
-        if page_num > TABLE_MAX_PAGES {
-            Err(PagerError::OutOfBounds {
-                page_num,
-                max_pages: TABLE_MAX_PAGES,
-            })
-        } else {
-            match self.pages[page_num] {
-                Some(ref page) => {
-                    let _page = page;
-                    Ok(page)
-                }
-                None => {
-                    let mut new_page = self.allocate_page(page_num)
-                        .map_err(|_| PagerError::CouldNotRead)?;
-                    self.pages[page_num] = Some(new_page);
-                    if page_num >= self.num_pages {
-                        self.num_pages = page_num + 1;
-                    }
-                    Ok(self.pages[page_num].as_ref().unwrap())
-                }
-            }
-        }
-    }
-
-    fn get_page_mut<'a>(&'a mut self, page_num: usize) -> Result<&'a mut Node, PagerError> {
-        match self.pages[page_num] {
-            Some(ref mut page) => Ok(page),
-            None => {
-                let mut new_page = self.allocate_page(page_num)
-                    .map_err(|_| PagerError::CouldNotRead)?;
-                self.pages[page_num] = Some(new_page);
-                if page_num >= self.num_pages {
-                    self.num_pages = page_num + 1;
-                }
-                Ok(self.pages[page_num].as_mut().unwrap())
-                // match self.pages[page_num as usize] {
-                //     Some(ref mut page) => page.borrow_mut(),
-                //     _ => unreachable!(),
-                // }
-            }
-        }
-    }
-
-    fn allocate_page(&mut self, page_num: usize) -> io::Result<Box<Node>> {
-        let num_pages = self.file_size as usize / PAGE_SIZE
-            + ((self.file_size as usize % PAGE_SIZE != 0) as usize);
-
-        Ok(if page_num < num_pages {
-            self.fd
-                .seek(io::SeekFrom::Start((page_num * PAGE_SIZE) as u64))?;
-            deserialize_from(&mut self.fd, Infinite).unwrap()
-        } else {
-            Box::new(Node::create_leaf())
-        })
-    }
-
-    fn flush_page(&mut self, page_num: usize) -> Result<(), io::Error> {
-        if let Some(ref page) = self.pages[page_num] {
-            self.fd
-                .seek(io::SeekFrom::Start(page_num as u64 * PAGE_SIZE as u64))?;
-            serialize_into(&mut self.fd, &page, Infinite)
-                .map_err(|_| io::Error::from(io::ErrorKind::Other))?;
-        }
-        Ok(())
-    }
-}
-
-const TABLE_MAX_PAGES: usize = 100;
-
-struct Table {
-    pager: Box<Pager>,
-    root_page_num: u32,
-}
-
-impl<'a> Table {
-    fn new(pager: Box<Pager>) -> Self {
-        Table {
-            root_page_num: 0,
-            pager,
-        }
-    }
-
-    fn find(&mut self, key: u32) -> Box<Cursor> {
-        let root_page_num = self.root_page_num;
-        let index = {
-            if let Node::Leaf {
-                num_cells, cells, ..
-            } = self.pager.get_page(root_page_num as usize).unwrap()
-            {
-                match cells[..*num_cells as usize]
-                    .binary_search_by_key(&key, |&btree::Cell(k, _)| k)
-                {
-                    Ok(idx) => idx,
-                    Err(idx) => idx,
-                }
-            } else {
-                unimplemented!("Can't search internal nodes yet")
-            }
-        };
-        Box::new(Cursor {
-            table: self,
-            page_num: root_page_num,
-            cell_num: index as u32,
-            end_of_table: false,
-        })
-    }
-
-    fn start(&mut self) -> Box<Cursor> {
-        let page_num = self.root_page_num;
-        let end_of_table = match self.pager.get_page(page_num as usize).unwrap() {
-            &Node::Leaf { num_cells, .. } => num_cells == 0,
-            _ => unimplemented!("Internal node"),
-        };
-        Box::new(Cursor {
-            table: self,
-            page_num,
-            cell_num: 0,
-            end_of_table,
-        })
-    }
-
-    fn end(&mut self) -> Box<Cursor> {
-        let page_num = self.root_page_num;
-        let cell_num = match self.pager.get_page(self.root_page_num as usize).unwrap() {
-            &Node::Leaf { num_cells, .. } => num_cells,
-            _ => unimplemented!("Internal node"),
-        };
-        Box::new(Cursor {
-            table: self,
-            page_num,
-            cell_num,
-            end_of_table: true,
-        })
-    }
-}
+/// Number of operations run by `--fuzz-seed`.
+const FUZZ_OPS: usize = 200;
 
 enum MetaCommand {
     Exit,
     PrintConstants,
     PrintTree,
+    PrintTreeJson,
+    ScanLimit(Option<u32>),
+    MemUse,
+    Import(String),
+    Prompt(String),
+    TreeStats,
+    Preallocate(usize),
+    DbInfo,
+    CacheStats,
+    ResetCacheStats,
+    Dump(String),
+    DumpTab(String),
+    ImportDump(String),
+    Load(String),
+    FillStats,
+    Open(String),
+    Layout,
+    Relink,
 }
 
 fn do_meta_command(command: &str) -> Result<MetaCommand, ParseError> {
@@ -265,98 +49,174 @@ fn do_meta_command(command: &str) -> Result<MetaCommand, ParseError> {
     } else if command.starts_with(".constants") {
         Ok(MetaCommand::PrintConstants)
     } else if command.starts_with(".btree") {
-        Ok(MetaCommand::PrintTree)
+        match command.split_whitespace().nth(1) {
+            None => Ok(MetaCommand::PrintTree),
+            Some("json") => Ok(MetaCommand::PrintTreeJson),
+            Some(_) => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".preallocate") {
+        let mut tokens = command.split_whitespace().skip(1);
+        match (tokens.next().map(|n| n.parse::<usize>()), tokens.next()) {
+            (Some(Ok(n)), None) => Ok(MetaCommand::Preallocate(n)),
+            _ => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".cachestats") {
+        match command.split_whitespace().nth(1) {
+            None => Ok(MetaCommand::CacheStats),
+            Some("reset") => Ok(MetaCommand::ResetCacheStats),
+            Some(_) => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".dbinfo") {
+        Ok(MetaCommand::DbInfo)
+    } else if command.starts_with(".open") {
+        let mut tokens = command.split_whitespace().skip(1);
+        match (tokens.next(), tokens.next()) {
+            (Some(filename), None) => Ok(MetaCommand::Open(filename.to_string())),
+            _ => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".relink") {
+        Ok(MetaCommand::Relink)
+    } else if command.starts_with(".layout") {
+        Ok(MetaCommand::Layout)
+    } else if command.starts_with(".fillstats") {
+        Ok(MetaCommand::FillStats)
+    } else if command.starts_with(".treestats") {
+        Ok(MetaCommand::TreeStats)
+    } else if command.starts_with(".memuse") {
+        Ok(MetaCommand::MemUse)
+    } else if command.starts_with(".prompt") {
+        // Everything after the command is the prompt; quotes allow trailing spaces.
+        let arg = command[".prompt".len()..].trim();
+        if arg.len() >= 2 && arg.starts_with('"') && arg.ends_with('"') {
+            Ok(MetaCommand::Prompt(arg[1..arg.len() - 1].to_string()))
+        } else {
+            Ok(MetaCommand::Prompt(arg.to_string()))
+        }
+    } else if command.starts_with(".dump") || command.starts_with(".import-dump") {
+        let mut tokens = command.split_whitespace();
+        match (tokens.next(), tokens.next(), tokens.next(), tokens.next()) {
+            (Some(".dump"), Some("--tab"), Some(filename), None) => {
+                Ok(MetaCommand::DumpTab(filename.to_string()))
+            }
+            (Some(".dump"), Some(filename), None, None) => {
+                Ok(MetaCommand::Dump(filename.to_string()))
+            }
+            (Some(".import-dump"), Some(filename), None, None) => {
+                Ok(MetaCommand::ImportDump(filename.to_string()))
+            }
+            _ => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".load") {
+        let mut tokens = command.split_whitespace().skip(1);
+        match (tokens.next(), tokens.next()) {
+            (Some(filename), None) => Ok(MetaCommand::Load(filename.to_string())),
+            _ => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".import") {
+        // There is only the one fixed-schema table, so `--create` and the
+        // table name are accepted for forward compatibility but change nothing.
+        let mut tokens = command.split_whitespace().skip(1).skip_while(|&t| t == "--create");
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(filename), _, None) => Ok(MetaCommand::Import(filename.to_string())),
+            _ => Err(ParseError::InvalidSyntax),
+        }
+    } else if command.starts_with(".scanlimit") {
+        let mut tokens = command.split_whitespace();
+        let _ = tokens.next(); // skip ".scanlimit"
+        match (tokens.next(), tokens.next()) {
+            (Some("off"), None) => Ok(MetaCommand::ScanLimit(None)),
+            (Some(n), None) => n.parse::<u32>()
+                .map(|n| MetaCommand::ScanLimit(Some(n)))
+                .map_err(|_| ParseError::InvalidSyntax),
+            _ => Err(ParseError::InvalidSyntax),
+        }
     } else {
         Err(ParseError::Unrecognized)
     }
 }
 
-enum ParseError {
-    Unrecognized,
-    InvalidSyntax,
-    StringTooLong,
-    NegativeID,
-}
-
-enum Statement {
-    Insert(Row),
-    Select,
+fn print_import_result(filename: &str, result: Result<csv::ImportSummary, csv::ImportError>) {
+    match result {
+        Ok(summary) => println!(
+            "Loaded {} rows, rejected {} duplicates and {} invalid records.",
+            summary.loaded, summary.duplicates, summary.invalid
+        ),
+        Err(csv::ImportError::Io(e)) => println!("Could not import {}: {}", filename, e),
+        Err(csv::ImportError::HeaderMismatch(header)) => println!(
+            "Error: expected CSV header '{}', found '{}'.",
+            csv::HEADER, header
+        ),
+    }
 }
 
-fn prepare_statement(input: &str) -> Result<Statement, ParseError> {
-    if input.starts_with("insert") {
-        let mut tokens = input.split_whitespace();
-        let _ = tokens.next(); // skip "insert"
-        match (tokens.next(), tokens.next(), tokens.next()) {
-            (Some(id_str), Some(username), Some(email)) => {
-                let id = id_str
-                    .parse::<i32>()
-                    .map_err(|_| ParseError::InvalidSyntax)?;
-                if id < 0 {
-                    return Err(ParseError::NegativeID);
-                }
-                let (uname_bytes, email_bytes) = (username.as_bytes(), email.as_bytes());
-                if uname_bytes.len() > MAX_UNAME_LENGTH || email_bytes.len() > MAX_EMAIL_LENGTH {
-                    return Err(ParseError::StringTooLong);
-                }
-                Ok(Statement::Insert(Row::new(
-                    id as u32,
-                    uname_bytes,
-                    email_bytes,
-                )))
+/// Prints what a statement produced the way the REPL always has, ahead of
+/// "Executed.".
+fn print_output(output: &Output) {
+    match *output {
+        Output::Done => {}
+        Output::Rows(ref rows) => for row in rows {
+            println!("{}", row);
+        },
+        Output::PartialRows(ref rows) => {
+            for row in rows {
+                println!("{}", row);
             }
-            _ => Err(ParseError::InvalidSyntax),
+            println!("partial result (budget exhausted)");
         }
-    } else if input.starts_with("select") {
-        Ok(Statement::Select)
-    } else {
-        Err(ParseError::Unrecognized)
+        Output::Deleted(count) => println!("Deleted {} rows.", count),
     }
 }
 
-enum ExecuteError {
-    DuplicateKey,
-    TableFull,
+const DEFAULT_PROMPT: &str = "db > ";
+
+/// Input lines longer than this are rejected before parsing.
+const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+enum InputLine {
+    Line(Vec<u8>),
+    TooLong,
+    Eof,
 }
 
-fn execute_insert(row: &Row, table: &mut Table) -> Result<(), ExecuteError> {
-    use btree::LEAF_NODE_MAX_CELLS;
-    let num_cells = match table.pager.get_page(table.root_page_num as usize).unwrap() {
-        &Node::Leaf { num_cells, .. } => num_cells as usize,
-        _ => unimplemented!("Internal node"),
-    };
-    if num_cells >= LEAF_NODE_MAX_CELLS {
-        return Err(ExecuteError::TableFull);
+/// Reads one line without its terminator, buffering at most `max_len` bytes.
+/// The rest of an over-long line is skipped rather than held in memory.
+fn read_input_line<R: BufRead>(reader: &mut R, max_len: usize) -> io::Result<InputLine> {
+    let mut line = Vec::new();
+    let read = reader
+        .by_ref()
+        .take(max_len as u64 + 1)
+        .read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(InputLine::Eof);
     }
-    {
-        let key_to_insert = row.id;
-        let mut cursor = table.find(key_to_insert);
-        if cursor.get_value().id == key_to_insert {
-            return Err(ExecuteError::DuplicateKey);
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
         }
-        cursor.insert(key_to_insert, row);
+        return Ok(InputLine::Line(line));
     }
-    Ok(())
-}
-
-fn execute_select(table: &mut Table) -> Result<(), ExecuteError> {
-    let mut cursor = table.start();
-    while !cursor.end_of_table {
-        println!("{}", cursor.get_value());
-        cursor.advance();
+    if line.len() <= max_len {
+        // Last line of the input, without a trailing newline.
+        return Ok(InputLine::Line(line));
     }
-    Ok(())
-}
-
-fn execute_statement(statement: Statement, table: &mut Table) -> Result<(), ExecuteError> {
-    match statement {
-        Statement::Insert(row) => execute_insert(&row, table),
-        Statement::Select => execute_select(table),
+    loop {
+        let (done, used) = {
+            let available = reader.fill_buf()?;
+            match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (true, i + 1),
+                None => (available.is_empty(), available.len()),
+            }
+        };
+        reader.consume(used);
+        if done {
+            return Ok(InputLine::TooLong);
+        }
     }
 }
 
-fn print_prompt() {
-    print!("db > ");
+fn print_prompt(prompt: &str) {
+    print!("{}", prompt);
     io::stdout().flush().unwrap();
 }
 
@@ -373,48 +233,166 @@ fn print_constants() {
     println!("LEAF_NODE_HEADER_SIZE: {}", leaf_node_header_size);
     println!("LEAF_NODE_CELL_SIZE: {}", leaf_node_cell_size);
     println!("LEAF_NODE_SPACE_FOR_CELLS: {}", leaf_node_space_for_cells);
-    println!("LEAF_NODE_MAX_CELLS: {}", btree::LEAF_NODE_MAX_CELLS);
+    println!("INTERNAL_NODE_CELL_SIZE: {}", size_of::<btree::InternalCell>());
+    println!("INTERNAL_NODE_MAX_CELLS: {}", btree::INTERNAL_NODE_MAX_CELLS);
 }
 
-fn db_open(filename: &str) -> Result<Table, io::Error> {
-    let mut pager = Pager::open(filename)?;
-    if pager.num_pages == 0 {
-        let _ = pager.get_page(0);
+/// Compares each on-disk type's logical payload with what it actually takes
+/// serialized and in memory, using rows of the maximum length and a leaf
+/// full of them.
+fn print_layout() {
+    let row = Row::new(0, &[b'u'; MAX_UNAME_LENGTH], &[b'e'; MAX_EMAIL_LENGTH]);
+    let row_payload = size_of::<Key>() + 1 + MAX_UNAME_LENGTH + 1 + MAX_EMAIL_LENGTH;
+    let row_serialized = row.to_bytes().len();
+    let cell_serialized = serialize(&btree::Cell(row), Infinite).unwrap().len();
+    let mut leaf = Node::create_leaf();
+    let mut num_cells = 0;
+    let mut next = row;
+    while leaf.insert(num_cells, &next).is_ok() {
+        num_cells += 1;
+        next.id += 1;
     }
-    let table = Table::new(pager);
-    Ok(table)
-}
-
-fn db_close(table: &mut Table) -> Result<(), io::Error> {
-    for i in 0..table.pager.num_pages {
-        table.pager.flush_page(i)?;
-    }
-    Ok(())
+    let leaf_payload = num_cells as usize * row_payload;
+    let leaf_serialized = leaf.encoded_len();
+    println!(
+        "Row: payload {}, serialized {}, in memory {}",
+        row_payload,
+        row_serialized,
+        size_of::<Row>()
+    );
+    println!(
+        "Cell: payload {}, serialized {}, in memory {}",
+        row_payload,
+        cell_serialized,
+        size_of::<btree::Cell>()
+    );
+    println!(
+        "Leaf node: payload {}, serialized {}, in memory {}, unused page bytes {}",
+        leaf_payload,
+        leaf_serialized,
+        size_of::<Node>() + num_cells as usize * size_of::<btree::Cell>(),
+        btree::NODE_SPACE - leaf_serialized
+    );
 }
 
 fn main() {
-    let mut args = env::args();
-    let filename = match (args.next(), args.next()) {
-        (_, None) => {
+    let mut args = env::args().skip(1);
+    let mut filename = None;
+    let mut sync_writes = false;
+    let mut prompt = DEFAULT_PROMPT.to_string();
+    let mut key = None;
+    let mut max_line_length = DEFAULT_MAX_LINE_LENGTH;
+    let mut preallocate = None;
+    let mut cache_size = DEFAULT_CACHE_SIZE;
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--fuzz-seed" => {
+                let seed = match args.next().map(|s| s.parse::<u64>()) {
+                    Some(Ok(seed)) => seed,
+                    _ => {
+                        println!("--fuzz-seed requires a numeric seed.");
+                        std::process::exit(1);
+                    }
+                };
+                let path = env::temp_dir().join(format!("db_tutorial_fuzz_{}.db", seed));
+                match fuzz::run(seed, FUZZ_OPS, &path.to_string_lossy()) {
+                    Ok(_) => println!("Fuzz seed {}: {} operations OK.", seed, FUZZ_OPS),
+                    Err(failure) => {
+                        print!("Fuzz seed {}: {}", seed, failure);
+                        std::process::exit(1);
+                    }
+                }
+                return;
+            }
+            "--version" => {
+                println!(
+                    "db_tutorial {} (format version {})",
+                    env!("CARGO_PKG_VERSION"),
+                    meta::FORMAT_VERSION
+                );
+                return;
+            }
+            "--sync-writes" => sync_writes = true,
+            "--key" => match args.next() {
+                Some(k) => key = Some(k),
+                None => {
+                    println!("--key requires a passphrase.");
+                    std::process::exit(1);
+                }
+            },
+            "--preallocate" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => preallocate = Some(n),
+                _ => {
+                    println!("--preallocate requires a number of pages.");
+                    std::process::exit(1);
+                }
+            },
+            "--cache-size" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => cache_size = n,
+                _ => {
+                    println!("--cache-size requires a number of pages.");
+                    std::process::exit(1);
+                }
+            },
+            "--max-line-length" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => max_line_length = n,
+                _ => {
+                    println!("--max-line-length requires a number of bytes.");
+                    std::process::exit(1);
+                }
+            },
+            "--prompt" => match args.next() {
+                Some(p) => prompt = p,
+                None => {
+                    println!("--prompt requires a prompt string.");
+                    std::process::exit(1);
+                }
+            },
+            _ => filename = Some(arg.clone()),
+        }
+    }
+    let mut filename = match filename {
+        Some(filename) => filename,
+        None => {
             println!("Must supply a database filename.");
             std::process::exit(1);
         }
-        (_, Some(filename)) => filename,
     };
 
-    let mut table = match db_open(&filename) {
+    let mut table = match db_open_with_key(&filename, key.as_ref().map(|k| &k[..])) {
         Ok(t) => t,
         Err(e) => {
             println!("Could not open file {}: {}", filename, e);
             std::process::exit(1);
         }
     };
+    table.pager.sync_writes = sync_writes;
+    table.pager.cache_size = cache_size;
+    if let Some(n) = preallocate {
+        if let Err(e) = table.pager.preallocate(n) {
+            println!("Could not preallocate {}: {:?}", filename, e);
+            std::process::exit(1);
+        }
+    }
 
-    let stdin = io::stdin();
-    let lines = stdin.lock().lines();
+    let mut scan_budget = None;
+    // Filled in place by each insert.
+    let mut row = Row::default();
 
-    print_prompt();
-    for line in lines {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+
+    print_prompt(&prompt);
+    loop {
+        let line = match read_input_line(&mut reader, max_line_length) {
+            Ok(InputLine::Line(bytes)) => String::from_utf8(bytes),
+            Ok(InputLine::TooLong) => {
+                println!("Error: input line too long.");
+                print_prompt(&prompt);
+                continue;
+            }
+            Ok(InputLine::Eof) | Err(_) => break,
+        };
         if let Ok(input) = line {
             if let Some('.') = input.chars().next() {
                 match do_meta_command(&input) {
@@ -425,30 +403,170 @@ fn main() {
                     }
                     Ok(MetaCommand::PrintTree) => {
                         println!("Tree:");
-                        print!("{:?}", table.pager.get_page(0).unwrap());
+                        let root_page_num = table.root_page_num;
+                        let mut tree = String::new();
+                        match table.is_empty() {
+                            Ok(true) => println!("(empty database)"),
+                            Ok(false) => match table.write_tree(root_page_num, 1, &mut tree) {
+                                Ok(()) => print!("{}", tree),
+                                Err(e) => println!("Error: {}.", e),
+                            },
+                            Err(e) => println!("Error: {}.", e),
+                        }
+                    }
+                    Ok(MetaCommand::PrintTreeJson) => match table.tree_to_json() {
+                        Ok(json) => println!("{}", json),
+                        Err(e) => println!("Error: {}.", e),
+                    },
+                    Ok(MetaCommand::ScanLimit(budget)) => scan_budget = budget,
+                    Ok(MetaCommand::MemUse) => {
+                        println!("Memory:");
+                        println!("resident pages: {}", table.pager.resident_pages());
+                        println!("approx bytes: {}", table.pager.resident_bytes());
+                    }
+                    Ok(MetaCommand::Prompt(p)) => prompt = p,
+                    Ok(MetaCommand::Preallocate(n)) => match table.pager.preallocate(n) {
+                        Ok(()) => println!("Preallocated {} pages.", n),
+                        Err(e) => println!("Could not preallocate: {}", e),
+                    },
+                    Ok(MetaCommand::CacheStats) => {
+                        let stats = &table.pager.cache_stats;
+                        println!("Cache stats:");
+                        println!("hits: {}", stats.hits);
+                        println!("misses: {}", stats.misses);
+                        println!("hit ratio: {:.1}%", stats.hit_ratio());
+                        println!("evictions: {}", stats.evictions);
+                    }
+                    Ok(MetaCommand::ResetCacheStats) => {
+                        table.pager.cache_stats = CacheStats::default()
+                    }
+                    Ok(MetaCommand::DbInfo) => {
+                        let counts = match table.is_empty() {
+                            Ok(true) => Ok((0, 0)),
+                            Ok(false) => table
+                                .tree_stats()
+                                .map(|stats| (stats.leaf_cells, stats.height)),
+                            Err(e) => Err(e),
+                        };
+                        // A new database has no metadata page until its first flush.
+                        let stored = table.stored_meta().map(|stored| match stored {
+                            Some(page) => (page.version.to_string(), page.page_size.to_string()),
+                            None => (
+                                format!("{} (unsaved)", meta::FORMAT_VERSION),
+                                format!("{} (unsaved)", PAGE_SIZE),
+                            ),
+                        });
+                        match (counts, stored) {
+                            (Err(e), _) => println!("Error: {}.", e),
+                            (_, Err(e)) => println!("Error: {}.", e),
+                            (Ok((rows, height)), Ok((version, page_size))) => {
+                                println!("Database info:");
+                                println!("format version: {}", version);
+                                println!("page size: {}", page_size);
+                                println!("root page: {}", table.root_page_num);
+                                println!("rows: {}", rows);
+                                println!("tree height: {}", height);
+                            }
+                        }
+                    }
+                    Ok(MetaCommand::TreeStats) => match table.tree_stats() {
+                        Ok(stats) => {
+                            println!("Tree stats:");
+                            println!("leaf pages: {}", stats.leaves);
+                            println!("internal pages: {}", stats.internals);
+                            println!("height: {}", stats.height);
+                            println!("average leaf fill: {:.1}%", stats.average_leaf_fill());
+                        }
+                        Err(e) => println!("Error: {}.", e),
+                    },
+                    Ok(MetaCommand::Dump(filename)) => match csv::export(&mut table, &filename) {
+                        Ok(n) => println!("Dumped {} rows.", n),
+                        Err(e) => println!("Could not dump to {}: {}", filename, e),
+                    },
+                    Ok(MetaCommand::DumpTab(filename)) => match dump::dump(&mut table, &filename) {
+                        Ok(n) => println!("Dumped {} rows.", n),
+                        Err(e) => println!("Could not dump to {}: {}", filename, e),
+                    },
+                    Ok(MetaCommand::ImportDump(filename)) => {
+                        let result = dump::restore(&mut table, &filename);
+                        print_import_result(&filename, result.map_err(csv::ImportError::Io))
+                    }
+                    Ok(MetaCommand::Open(new_filename)) => {
+                        // The current table stays open if the new one can't be.
+                        if let Err(e) = db_close(&mut table) {
+                            println!("Could not close db {}: {}", filename, e);
+                        } else {
+                            match db_open_with_key(&new_filename, key.as_ref().map(|k| &k[..])) {
+                                Ok(t) => {
+                                    table = t;
+                                    table.pager.sync_writes = sync_writes;
+                                    table.pager.cache_size = cache_size;
+                                    filename = new_filename;
+                                }
+                                Err(e) => println!("Could not open file {}: {}", new_filename, e),
+                            }
+                        }
+                    }
+                    Ok(MetaCommand::Relink) => match table.relink() {
+                        Ok(n) => println!("Relinked {} leaves.", n),
+                        Err(e) => println!("Error: {}.", e),
+                    },
+                    Ok(MetaCommand::Layout) => {
+                        println!("Layout:");
+                        print_layout();
+                    }
+                    Ok(MetaCommand::FillStats) => match table.tree_stats() {
+                        Ok(stats) => {
+                            println!("Fill stats:");
+                            println!("leaf pages: {}", stats.leaves);
+                            println!("average leaf fill: {:.1}%", stats.average_leaf_fill());
+                            println!("minimum leaf fill: {:.1}%", stats.min_leaf_fill());
+                        }
+                        Err(e) => println!("Error: {}.", e),
+                    },
+                    Ok(MetaCommand::Import(filename)) => {
+                        print_import_result(&filename, csv::import(&mut table, &filename))
+                    }
+                    Ok(MetaCommand::Load(filename)) => {
+                        print_import_result(&filename, dump::load(&mut table, &filename))
                     }
                     Err(ParseError::Unrecognized) => println!("Unrecognized command '{}'", input),
+                    Err(ParseError::InvalidSyntax) => {
+                        println!("Syntax error: could not parse command.")
+                    }
                     _ => {}
                 }
             } else {
-                match prepare_statement(&input) {
-                    Ok(statement) => match execute_statement(statement, &mut table) {
-                        Ok(()) => println!("Executed."),
+                let statement = if input.starts_with("insert") {
+                    prepare_insert_into(&input, &mut row).map(|()| Statement::Insert(row))
+                } else {
+                    prepare_statement(&input)
+                };
+                match statement {
+                    Ok(statement) => match execute_statement(statement, &mut table, scan_budget) {
+                        Ok(output) => {
+                            print_output(&output);
+                            println!("Executed.");
+                        }
                         Err(ExecuteError::TableFull) => println!("Error: Table full."),
                         Err(ExecuteError::DuplicateKey) => println!("Error: Duplicate key."),
+                        Err(ExecuteError::NoSuchKey) => println!("Error: No such key."),
+                        Err(ExecuteError::InvalidRow(e)) => println!("Error: Invalid row ({:?}).", e),
+                        Err(ExecuteError::Pager(e)) => println!("Error: {}.", e),
                     },
                     Err(ParseError::Unrecognized) => {
                         println!("Unrecognized keyword at start of {}", input)
                     }
                     Err(ParseError::NegativeID) => println!("ID must be positive."),
                     Err(ParseError::StringTooLong) => println!("String is too long."),
+                    Err(ParseError::InvalidUtf8) => println!("Strings must be valid UTF-8."),
                     Err(ParseError::InvalidSyntax) => {
                         println!("Syntax error: could not parse statement.")
                     }
                 }
             }
         }
-        print_prompt();
+        print_prompt(&prompt);
     }
     if let Err(e) = db_close(&mut table) {
         println!("Could not close db {}: {}", filename, e);
@@ -0,0 +1,58 @@
+//! The metadata page. Page 0 of every file describes the rest of it; table
+//! data starts at page 1.
+
+use bincode::{deserialize, serialize, Infinite};
+use std::mem;
+
+use btree::{Key, PAGE_SIZE};
+
+pub const MAGIC: &[u8; 8] = b"DBTUTRL\0";
+/// Version of the on-disk layout this binary reads and writes. Version 1 had
+/// no metadata page and rooted the table at page 0; such files hold a single
+/// leaf, and are migrated when opened.
+pub const FORMAT_VERSION: u32 = 2;
+pub const META_PAGE_NUM: u32 = 0;
+/// Where the table's root lives in a new file.
+pub const DEFAULT_ROOT_PAGE_NUM: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    magic: [u8; 8],
+    pub version: u32,
+    pub page_size: u32,
+    /// The catalog. There is only one table, so it is just that table's root.
+    pub root_page_num: u32,
+    /// First page of the free list, or 0 (never a free page) if it's empty.
+    pub free_list_head: u32,
+    /// Pages in use, including this one. Preallocated pages past these are
+    /// still free.
+    pub num_pages: u32,
+    /// Bytes in a key, which depends on the `wide-keys` feature.
+    pub key_size: u32,
+}
+
+impl Meta {
+    pub fn new(root_page_num: u32, num_pages: u32) -> Self {
+        Meta {
+            magic: *MAGIC,
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            root_page_num,
+            free_list_head: 0,
+            num_pages,
+            key_size: mem::size_of::<Key>() as u32,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(self, Infinite).expect("serializing Meta into memory cannot fail")
+    }
+
+    /// Decodes a metadata page, or returns `None` if `bytes` isn't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Meta> {
+        match deserialize::<Meta>(bytes) {
+            Ok(meta) if &meta.magic == MAGIC => Some(meta),
+            _ => None,
+        }
+    }
+}
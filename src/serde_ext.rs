@@ -41,7 +41,7 @@ macro_rules! big_array {
                         type Value = [T; $len];
 
                         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                            formatter.write_str(concat!("an array of length ", $len))
+                            write!(formatter, "an array of length {}", $len)
                         }
 
                         fn visit_seq<A>(self, mut seq: A) -> Result<[T; $len], A::Error>
@@ -60,10 +60,27 @@ macro_rules! big_array {
                     deserializer.deserialize_tuple($len, visitor)
                 }
             }
+
+            impl<'de, T> BigArray<'de> for Box<[T; $len]>
+                where T: Default + Copy + Serialize + Deserialize<'de>
+            {
+                fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+                    where S: Serializer
+                {
+                    <[T; $len] as BigArray>::serialize(self, serializer)
+                }
+
+                fn deserialize<D>(deserializer: D) -> Result<Box<[T; $len]>, D::Error>
+                    where D: Deserializer<'de>
+                {
+                    <[T; $len] as BigArray>::deserialize(deserializer).map(Box::new)
+                }
+            }
         )+
     }
 }
 
 big_array! {
     255,
+    ::btree::INTERNAL_NODE_MAX_CELLS,
 }
\ No newline at end of file
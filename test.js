@@ -4,13 +4,15 @@ const assert = require("assert");
 
 describe("database", () => {
   beforeEach(() => {
-    try {
-      fs.unlinkSync("./test.db");
-    } catch (_) {}
+    for (const file of ["./test.db", "./test.csv", "./test.dump", "./other.db"]) {
+      try {
+        fs.unlinkSync(file);
+      } catch (_) {}
+    }
   });
 
-  function run_script(commands) {
-    const output = execFileSync("./target/debug/db_tutorial", ["./test.db"], {
+  function run_script(commands, flags = []) {
+    const output = execFileSync("./target/debug/db_tutorial", [...flags, "./test.db"], {
       input: commands.join("\n"),
       env: { RUST_BACKTRACE: 1 }
     });
@@ -43,15 +45,6 @@ describe("database", () => {
     ]);
   });
 
-  it("prints error message when table is full", () => {
-    const script = Array.from(Array(1400).keys()).map(
-      i => `insert ${i + 1} user${i + 1} person${i + 1}@example.com`
-    );
-    script.push(".exit");
-    const result = run_script(script);
-    assert.deepEqual(result[result.length - 2], "db > Error: Table full.");
-  });
-
   it("allows inserting strings that are the maximum length", () => {
     const long_username = "a".repeat(32);
     const long_email = "a".repeat(256);
@@ -103,9 +96,10 @@ describe("database", () => {
       "ROW_SIZE: 293",
       "COMMON_NODE_HEADER_SIZE: 6",
       "LEAF_NODE_HEADER_SIZE: 10",
-      "LEAF_NODE_CELL_SIZE: 297",
+      "LEAF_NODE_CELL_SIZE: 293",
       "LEAF_NODE_SPACE_FOR_CELLS: 4086",
-      "LEAF_NODE_MAX_CELLS: 13",
+      "INTERNAL_NODE_CELL_SIZE: 8",
+      "INTERNAL_NODE_MAX_CELLS: 509",
       "db > "
     ]);
   });
@@ -146,4 +140,594 @@ describe("database", () => {
       "db > "
     ]);
   });
+
+  it("stops a select once the scan budget is exhausted", () => {
+    const script = [1, 2, 3].map(
+      i => `insert ${i} user${i} person${i}@example.com`
+    );
+    script.push(".scanlimit 2", "select", ".scanlimit 3", "select", ".exit");
+    const result = run_script(script);
+    assert.deepEqual(result.slice(3), [
+      "db > db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "partial result (budget exhausted)",
+      "Executed.",
+      "db > db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "(3, user3, person3@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("orders a select by username", () => {
+    const script = [
+      "insert 1 carol carol@example.com",
+      "insert 2 alice alice@example.com",
+      "insert 3 bob bob@example.com",
+      "select order by username",
+      "select order by username desc",
+      ".exit"
+    ];
+    const result = run_script(script);
+    assert.deepEqual(result.slice(3), [
+      "db > (2, alice, alice@example.com)",
+      "(3, bob, bob@example.com)",
+      "(1, carol, carol@example.com)",
+      "Executed.",
+      "db > (1, carol, carol@example.com)",
+      "(3, bob, bob@example.com)",
+      "(2, alice, alice@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("reports resident pages and memory use", () => {
+    const script = ["insert 1 user1 person1@example.com", ".memuse", ".exit"];
+    const result = run_script(script);
+    assert.deepEqual(result, [
+      "db > Executed.",
+      "db > Memory:",
+      "resident pages: 1",
+      "approx bytes: 336",
+      "db > "
+    ]);
+  });
+
+  it("selects the first and last rows by id", () => {
+    const script = [5, 3, 1, 4, 2].map(
+      i => `insert ${i} user${i} person${i}@example.com`
+    );
+    script.push("select head 2", "select tail 3", "select tail 10", ".exit");
+    const result = run_script(script);
+    assert.deepEqual(result.slice(5), [
+      "db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "Executed.",
+      "db > (3, user3, person3@example.com)",
+      "(4, user4, person4@example.com)",
+      "(5, user5, person5@example.com)",
+      "Executed.",
+      "db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "(3, user3, person3@example.com)",
+      "(4, user4, person4@example.com)",
+      "(5, user5, person5@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("keeps data written with --sync-writes", () => {
+    const result1 = run_script(
+      ["insert 1 user1 person1@example.com", ".exit"],
+      ["--sync-writes"]
+    );
+    assert.deepEqual(result1, ["db > Executed.", "db > "]);
+
+    const result2 = run_script(["select", ".exit"], ["--sync-writes"]);
+    assert.deepEqual(result2, [
+      "db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("prints the btree as json", () => {
+    const script = [3, 1, 2].map(
+      i => `insert ${i} user${i} person${i}@example.com`
+    );
+    script.push(".btree json");
+    script.push(".exit");
+    const result = run_script(script);
+    const tree = JSON.parse(result[3].slice("db > ".length));
+    assert.deepEqual(tree, {
+      type: "leaf",
+      page: 1,
+      keys: [1, 2, 3],
+      children: []
+    });
+  });
+
+  it("prints a two-level tree as json", () => {
+    const script = Array.from(Array(14).keys()).map(
+      i => `insert ${i + 1} ${"u".repeat(32)} ${"e".repeat(255)}`
+    );
+    script.push(".btree json", ".exit");
+    const result = run_script(script);
+    const tree = JSON.parse(result[14].slice("db > ".length));
+    assert.deepEqual(tree, {
+      type: "internal",
+      page: 1,
+      keys: [7],
+      children: [
+        { type: "leaf", page: 3, keys: [1, 2, 3, 4, 5, 6, 7], children: [] },
+        { type: "leaf", page: 2, keys: [8, 9, 10, 11, 12, 13, 14], children: [] }
+      ]
+    });
+  });
+
+  it("imports rows from a csv file", () => {
+    fs.writeFileSync(
+      "./test.csv",
+      [
+        "id,username,email",
+        "2,user2,person2@example.com",
+        "1,user1,person1@example.com",
+        "1,dup,dup@example.com",
+        "x,bad,bad@example.com"
+      ].join("\n")
+    );
+    const result = run_script([".import --create ./test.csv users", "select", ".exit"]);
+    assert.deepEqual(result, [
+      "db > Loaded 2 rows, rejected 1 duplicates and 1 invalid records.",
+      "db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("rejects a csv file with the wrong header", () => {
+    fs.writeFileSync("./test.csv", "id,name\n1,user1\n");
+    const result = run_script([".import --create ./test.csv users", "select", ".exit"]);
+    assert.deepEqual(result, [
+      "db > Error: expected CSV header 'id,username,email', found 'id,name'.",
+      "db > Executed.",
+      "db > "
+    ]);
+  });
+
+  it("changes the prompt", () => {
+    const script = [".prompt \"sql> \"", "select", ".prompt", "select", ".exit"];
+    const result = run_script(script);
+    assert.deepEqual(result, ["db > sql> Executed.", "sql> Executed.", ""]);
+  });
+
+  it("accepts a prompt on the command line", () => {
+    const result = run_script(["select", ".exit"], ["--prompt", "> "]);
+    assert.deepEqual(result, ["> Executed.", "> "]);
+  });
+
+  it("requires the key to open an encrypted database", () => {
+    const result1 = run_script(
+      ["insert 1 user1 person1@example.com", ".exit"],
+      ["--key", "hunter2"]
+    );
+    assert.deepEqual(result1, ["db > Executed.", "db > "]);
+    assert.ok(!fs.readFileSync("./test.db").includes("person1@example.com"));
+
+    for (const flags of [[], ["--key", "wrong"], ["--key", "hunter3"]]) {
+      let error;
+      try {
+        run_script(["select", ".exit"], flags);
+      } catch (e) {
+        error = e;
+      }
+      assert.ok(error, `opened encrypted database with flags [${flags}]`);
+      const message = error.stdout.toString();
+      assert.ok(message.startsWith("Could not open file ./test.db: "), message);
+    }
+
+    const result2 = run_script(["select", ".exit"], ["--key", "hunter2"]);
+    assert.deepEqual(result2, [
+      "db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("rejects input lines that are too long", () => {
+    const script = [
+      "insert 1 user1 " + "a".repeat(70000),
+      "select",
+      ".exit"
+    ];
+    const result = run_script(script);
+    assert.deepEqual(result, [
+      "db > Error: input line too long.",
+      "db > Executed.",
+      "db > "
+    ]);
+
+    const result2 = run_script(["select " + "x".repeat(20), ".exit"], [
+      "--max-line-length",
+      "10"
+    ]);
+    assert.deepEqual(result2, ["db > Error: input line too long.", "db > "]);
+  });
+
+  it("selects rows by key range with inclusive and exclusive bounds", () => {
+    const script = [1, 2, 3, 4, 5].map(
+      i => `insert ${i} user${i} person${i}@example.com`
+    );
+    script.push(
+      "select where id > 2 and id < 5",
+      "select where id >= 2 and id <= 3",
+      "select where id > 4",
+      "select where id < 2",
+      "select where id > 3 and id < 4",
+      "select where id > 1 and id > 2",
+      ".exit"
+    );
+    const result = run_script(script);
+    assert.deepEqual(result.slice(5), [
+      "db > (3, user3, person3@example.com)",
+      "(4, user4, person4@example.com)",
+      "Executed.",
+      "db > (2, user2, person2@example.com)",
+      "(3, user3, person3@example.com)",
+      "Executed.",
+      "db > (5, user5, person5@example.com)",
+      "Executed.",
+      "db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > Executed.",
+      "db > Syntax error: could not parse statement.",
+      "db > "
+    ]);
+  });
+
+  it("prints tree statistics", () => {
+    const script = [1, 2, 3, 4].map(
+      i => `insert ${i} user${i} person${i}@example.com`
+    );
+    script.push(".treestats", ".exit");
+    const result = run_script(script);
+    assert.deepEqual(result.slice(4), [
+      "db > Tree stats:",
+      "leaf pages: 1",
+      "internal pages: 0",
+      "height: 1",
+      "average leaf fill: 3.5%",
+      "db > "
+    ]);
+  });
+
+  it("prints tree statistics for a two-level tree", () => {
+    const script = Array.from(Array(14).keys()).map(
+      i => `insert ${i + 1} ${"u".repeat(32)} ${"e".repeat(255)}`
+    );
+    script.push(".treestats", ".exit");
+    const result = run_script(script);
+    assert.deepEqual(result.slice(14), [
+      "db > Tree stats:",
+      "leaf pages: 2",
+      "internal pages: 1",
+      "height: 2",
+      "average leaf fill: 50.7%",
+      "db > "
+    ]);
+  });
+
+  it("prints a placeholder for the btree of an empty database", () => {
+    const result = run_script([".btree", ".exit"]);
+    assert.deepEqual(result, ["db > Tree:", "(empty database)", "db > "]);
+    assert.equal(fs.statSync("./test.db").size, 0);
+  });
+
+  it("loads the same data into a preallocated file", () => {
+    const inserts = Array.from(Array(10).keys()).map(
+      i => `insert ${i + 1} user${i + 1} person${i + 1}@example.com`
+    );
+    run_script([...inserts, ".exit"]);
+    const expected = run_script(["select", ".exit"]);
+
+    fs.unlinkSync("./test.db");
+    run_script([...inserts, ".exit"], ["--preallocate", "10"]);
+    assert.ok(fs.statSync("./test.db").size >= 10 * 4096);
+    assert.deepEqual(run_script(["select", ".exit"]), expected);
+
+    fs.unlinkSync("./test.db");
+    const result = run_script([".preallocate 4", ...inserts, ".exit"]);
+    assert.equal(result[0], "db > Preallocated 4 pages.");
+    assert.equal(fs.statSync("./test.db").size, 4 * 4096);
+    assert.deepEqual(run_script(["select", ".exit"]), expected);
+  });
+
+  it("prints database info", () => {
+    const result = run_script([
+      ".dbinfo",
+      "insert 1 user1 person1@example.com",
+      "insert 2 user2 person2@example.com",
+      ".dbinfo",
+      ".exit"
+    ]);
+    const info = (rows, saved) => [
+      "Database info:",
+      `format version: 2${saved ? "" : " (unsaved)"}`,
+      `page size: 4096${saved ? "" : " (unsaved)"}`,
+      "root page: 1",
+      `rows: ${rows}`,
+      `tree height: ${rows ? 1 : 0}`
+    ];
+    assert.deepEqual(result, [
+      "db > " + info(0, false)[0],
+      ...info(0, false).slice(1),
+      "db > Executed.",
+      "db > Executed.",
+      "db > " + info(2, false)[0],
+      ...info(2, false).slice(1),
+      "db > "
+    ]);
+
+    // Once closed, the version and page size come from the file itself.
+    const reopened = run_script([".dbinfo", ".exit"]);
+    assert.deepEqual(reopened, ["db > " + info(2, true)[0], ...info(2, true).slice(1), "db > "]);
+  });
+
+  it("prints the supported format version", () => {
+    const result = run_script([], ["--version"]);
+    assert.deepEqual(result, ["db_tutorial 0.1.0 (format version 2)", ""]);
+  });
+
+  it("refuses to open a file with a torn page", () => {
+    run_script(["insert 1 user1 person1@example.com", ".exit"]);
+    // Partway through the row, which only takes the first few dozen bytes.
+    fs.truncateSync("./test.db", 4096 + 20);
+
+    let error;
+    try {
+      run_script(["select", ".exit"]);
+    } catch (e) {
+      error = e;
+    }
+    assert.ok(error, "opened a database with a torn page");
+    assert.equal(
+      error.stdout.toString(),
+      "Could not open file ./test.db: page 1 is torn or corrupt\n"
+    );
+  });
+
+  it("refuses to open a file that isn't a database", () => {
+    fs.writeFileSync("./test.db", "id,username,email\n1,user1,person1@example.com\n");
+
+    let error;
+    try {
+      run_script(["select", ".exit"]);
+    } catch (e) {
+      error = e;
+    }
+    assert.ok(error, "opened a file that isn't a database");
+    assert.equal(
+      error.stdout.toString(),
+      "Could not open file ./test.db: not a valid database file\n"
+    );
+  });
+
+  it("reports a high cache hit ratio for a resident working set", () => {
+    const script = [1, 2, 3].map(
+      i => `insert ${i} user${i} person${i}@example.com`
+    );
+    script.push("select", "select", "select", ".cachestats");
+    script.push(".cachestats reset", ".cachestats", ".exit");
+    const result = run_script(script);
+    const stats = result.slice(result.indexOf("db > Cache stats:"));
+    assert.equal(stats[2], "misses: 1");
+    const ratio = parseFloat(stats[3].slice("hit ratio: ".length));
+    assert.ok(ratio > 90, stats[3]);
+    assert.equal(stats[4], "evictions: 0");
+
+    assert.deepEqual(stats.slice(5), [
+      "db > db > Cache stats:",
+      "hits: 0",
+      "misses: 0",
+      "hit ratio: 0.0%",
+      "evictions: 0",
+      "db > "
+    ]);
+  });
+
+  it("round-trips fields with spaces through .dump --tab and .import-dump", () => {
+    fs.writeFileSync(
+      "./test.csv",
+      ["id,username,email", "1,John Smith,john smith@example.com", "2,a\tb,c\\d"].join("\n")
+    );
+    const dumped = run_script([".import ./test.csv", ".dump --tab ./test.dump", ".exit"]);
+    assert.deepEqual(dumped, [
+      "db > Loaded 2 rows, rejected 0 duplicates and 0 invalid records.",
+      "db > Dumped 2 rows.",
+      "db > "
+    ]);
+    assert.equal(
+      fs.readFileSync("./test.dump", "utf8"),
+      "insert\t1\tJohn Smith\tjohn smith@example.com\ninsert\t2\ta\\tb\tc\\\\d\n"
+    );
+
+    fs.unlinkSync("./test.db");
+    const result = run_script([".import-dump ./test.dump", "select", ".exit"]);
+    assert.deepEqual(result, [
+      "db > Loaded 2 rows, rejected 0 duplicates and 0 invalid records.",
+      "db > (1, John Smith, john smith@example.com)",
+      "(2, a\tb, c\\d)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("dumps rows to csv in id order and loads them back with .load", () => {
+    const dumped = run_script([
+      "insert 2 bob bob@example.com",
+      "insert 1 alice alice@example.com",
+      'insert 3 "smith, jr" smith@example.com',
+      ".dump ./test.csv",
+      ".exit"
+    ]);
+    assert.equal(dumped[3], "db > Dumped 3 rows.");
+    assert.equal(
+      fs.readFileSync("./test.csv", "utf8"),
+      "id,username,email\n1,alice,alice@example.com\n2,bob,bob@example.com\n" +
+        '3,"smith, jr",smith@example.com\n'
+    );
+
+    fs.appendFileSync("./test.csv", "2,bob,bob@example.com\n-4,eve,eve@example.com\n");
+    fs.unlinkSync("./test.db");
+    const result = run_script([".load ./test.csv", "select", ".exit"]);
+    assert.deepEqual(result, [
+      "db > Loaded 3 rows, rejected 1 duplicates and 1 invalid records.",
+      "db > (1, alice, alice@example.com)",
+      "(2, bob, bob@example.com)",
+      "(3, smith, jr, smith@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("allows spaces in quoted fields", () => {
+    const result = run_script([
+      'insert 1 "Ada Lovelace" ada@example.com',
+      'insert 2 "Alan Turing ada@example.com',
+      "select",
+      ".exit"
+    ]);
+    assert.deepEqual(result, [
+      "db > Executed.",
+      "db > Syntax error: could not parse statement.",
+      "db > (1, Ada Lovelace, ada@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("finds nothing to relink in a healthy tree", () => {
+    const script = Array.from(Array(40).keys()).map(
+      i => `insert ${i + 1} ${"u".repeat(32)} ${"e".repeat(255)}`
+    );
+    script.push(".relink", ".exit");
+    assert.deepEqual(run_script(script).slice(40), ["db > Relinked 0 leaves.", "db > "]);
+  });
+
+  it("deletes a range of rows", () => {
+    const script = Array.from(Array(10).keys()).map(
+      i => `insert ${i + 1} user${i + 1} person${i + 1}@example.com`
+    );
+    script.push("delete where id >= 3 and id <= 8", "delete where id > 100", ".exit");
+    assert.deepEqual(run_script(script).slice(10), [
+      "db > Deleted 6 rows.",
+      "Executed.",
+      "db > Deleted 0 rows.",
+      "Executed.",
+      "db > "
+    ]);
+    const result = run_script(["select", ".btree", ".exit"]);
+    assert.deepEqual(result, [
+      "db > (1, user1, person1@example.com)",
+      "(2, user2, person2@example.com)",
+      "(9, user9, person9@example.com)",
+      "(10, user10, person10@example.com)",
+      "Executed.",
+      "db > Tree:",
+      "leaf (size 4)",
+      "  - 0 : 1",
+      "  - 1 : 2",
+      "  - 2 : 9",
+      "  - 3 : 10",
+      "db > "
+    ]);
+  });
+
+  it("reports leaf fill before and after deletes", () => {
+    const script = Array.from(Array(13).keys()).map(
+      i => `insert ${i + 1} user${i + 1} person${i + 1}@example.com`
+    );
+    script.push(".fillstats", "delete where id <= 5", ".fillstats", ".exit");
+    const result = run_script(script);
+    assert.deepEqual(result.slice(result.indexOf("db > Fill stats:")), [
+      "db > Fill stats:",
+      "leaf pages: 1",
+      "average leaf fill: 10.3%",
+      "minimum leaf fill: 10.3%",
+      "db > Deleted 5 rows.",
+      "Executed.",
+      "db > Fill stats:",
+      "leaf pages: 1",
+      "average leaf fill: 6.7%",
+      "minimum leaf fill: 6.7%",
+      "db > "
+    ]);
+  });
+
+  it("reports leaf fill for a multi-leaf tree before and after deletes", () => {
+    const script = Array.from(Array(40).keys()).map(
+      i => `insert ${i + 1} ${"u".repeat(32)} ${"e".repeat(255)}`
+    );
+    script.push(".fillstats");
+    // Thin out every leaf without emptying any of them.
+    for (const start of [2, 9, 16, 23, 30]) {
+      script.push(`delete where id >= ${start} and id <= ${start + 3}`);
+    }
+    script.push(".fillstats", ".exit");
+    const result = run_script(script);
+    const stats = result.filter(line => line.includes("fill") || line.startsWith("leaf"));
+    assert.deepEqual(stats, [
+      "leaf pages: 5",
+      "average leaf fill: 57.8%",
+      "minimum leaf fill: 50.7%",
+      "leaf pages: 5",
+      "average leaf fill: 29.2%",
+      "minimum leaf fill: 22.1%"
+    ]);
+  });
+
+  it("switches databases with .open", () => {
+    const result = run_script([
+      "insert 1 user1 person1@example.com",
+      ".open ./other.db",
+      "insert 2 user2 person2@example.com",
+      "select",
+      ".open ./test.db",
+      "select",
+      ".exit"
+    ]);
+    assert.deepEqual(result, [
+      "db > Executed.",
+      "db > db > Executed.",
+      "db > (2, user2, person2@example.com)",
+      "Executed.",
+      "db > db > (1, user1, person1@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+
+    const other = execFileSync("./target/debug/db_tutorial", ["./other.db"], {
+      input: "select\n.exit"
+    });
+    assert.deepEqual(other.toString().split("\n"), [
+      "db > (2, user2, person2@example.com)",
+      "Executed.",
+      "db > "
+    ]);
+  });
+
+  it("prints the layout of on-disk types", () => {
+    const result = run_script([".layout", ".exit"]);
+    assert.deepEqual(result, [
+      "db > Layout:",
+      "Row: payload 293, serialized 293, in memory 296",
+      "Cell: payload 293, serialized 293, in memory 296",
+      "Leaf node: payload 3809, serialized 3834, in memory 3888, unused page bytes 258",
+      "db > "
+    ]);
+  });
 });